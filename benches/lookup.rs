@@ -0,0 +1,62 @@
+use std::any::Any;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use typemap::{Key, SmallTypeMap, TypeMap};
+
+macro_rules! keys {
+    ($($key:ident),*) => {
+        $(struct $key; impl Key for $key { type Value = usize; })*
+
+        fn fill<S: BuildHasher, const N: usize>(map: &mut TypeMap<dyn Any, S, N>) {
+            $(map.insert::<$key>(0);)*
+        }
+
+        fn lookup<S: BuildHasher, const N: usize>(map: &TypeMap<dyn Any, S, N>) -> usize {
+            0 $(+ *map.get::<$key>().unwrap())*
+        }
+    }
+}
+
+keys!(K0, K1, K2, K3, K4, K5, K6, K7);
+
+fn bench_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get 8 keys");
+
+    let mut identity = TypeMap::new();
+    fill(&mut identity);
+    group.bench_function("TypeIdHasher", |b| b.iter(|| lookup(black_box(&identity))));
+
+    let mut sip: TypeMap<dyn Any, RandomState> = TypeMap::default();
+    fill(&mut sip);
+    group.bench_function("SipHash", |b| b.iter(|| lookup(black_box(&sip))));
+
+    let mut small = SmallTypeMap::<8>::custom();
+    fill(&mut small);
+    group.bench_function("SmallTypeMap<8>", |b| b.iter(|| lookup(black_box(&small))));
+
+    group.finish();
+}
+
+fn bench_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("create and insert 8 keys");
+
+    group.bench_function("TypeIdHasher", |b| b.iter(|| {
+        let mut map = TypeMap::new();
+        fill(&mut map);
+        black_box(map)
+    }));
+
+    group.bench_function("SmallTypeMap<8>", |b| b.iter(|| {
+        let mut map = SmallTypeMap::<8>::custom();
+        fill(&mut map);
+        black_box(map)
+    }));
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_lookup, bench_build);
+criterion_main!(benches);
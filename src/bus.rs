@@ -0,0 +1,203 @@
+//! An event bus which routes each event to the subscribers of its type.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::any::{type_name, Any};
+use core::fmt;
+use core::marker::PhantomData;
+
+use crate::{Key, TypeMap};
+
+type Handler<E> = Box<dyn FnMut(&E) + Send>;
+type Deliver = Box<dyn FnOnce(&mut TypeMap<dyn Any + Send>) + Send>;
+
+/// A bus of events, which calls the handlers subscribed to an event's type
+/// whenever one is published.
+///
+/// Handlers are called in the order they subscribed. A queued bus holds
+/// published events until `flush` is called, then delivers them in the
+/// order they were published.
+pub struct EventBus {
+    topics: TypeMap<dyn Any + Send>,
+    queue: Option<VecDeque<Deliver>>,
+    next_id: u64
+}
+
+/// A handle to a handler subscribed to events of type `E`, which can be
+/// passed to `EventBus::unsubscribe`.
+pub struct Subscription<E> {
+    id: u64,
+    event: PhantomData<fn(&E)>
+}
+
+struct Topic<E>(PhantomData<E>);
+
+impl<E: Any> Key for Topic<E> { type Value = Vec<(u64, Handler<E>)>; }
+
+impl EventBus {
+    /// Create a new EventBus, which delivers events as they are published.
+    pub fn new() -> EventBus {
+        EventBus { topics: TypeMap::custom(), queue: None, next_id: 0 }
+    }
+
+    /// Create a new EventBus, which holds events until they are flushed.
+    pub fn queued() -> EventBus {
+        EventBus { queue: Some(VecDeque::new()), ..EventBus::new() }
+    }
+
+    /// Call `handler` with every event of type `E` published from now on.
+    pub fn subscribe<E: Any, F>(&mut self, handler: F) -> Subscription<E>
+    where F: FnMut(&E) + Send + 'static {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.topics.entry::<Topic<E>>().or_default().push((id, Box::new(handler)));
+        Subscription { id, event: PhantomData }
+    }
+
+    /// Stop calling a subscribed handler.
+    ///
+    /// Returns false if the handler was already unsubscribed.
+    pub fn unsubscribe<E: Any>(&mut self, subscription: Subscription<E>) -> bool {
+        let Some(handlers) = self.topics.get_mut::<Topic<E>>() else { return false };
+        let len = handlers.len();
+        handlers.retain(|&(id, _)| id != subscription.id);
+        handlers.len() != len
+    }
+
+    /// Get the number of handlers subscribed to events of type `E`.
+    pub fn subscribers<E: Any>(&self) -> usize {
+        self.topics.get::<Topic<E>>().map_or(0, Vec::len)
+    }
+
+    /// Publish an event to the handlers subscribed to its type.
+    ///
+    /// If the bus is queued, the event is delivered by the next `flush`
+    /// instead, to the handlers subscribed at that time.
+    pub fn publish<E: Any + Send>(&mut self, event: E) {
+        match self.queue {
+            Some(ref mut queue) => queue.push_back(Box::new(move |topics| deliver(topics, &event))),
+            None => deliver(&mut self.topics, &event)
+        }
+    }
+
+    /// Deliver the events queued since the last flush.
+    ///
+    /// Returns the number of events delivered, which is always 0 if the
+    /// bus is not queued.
+    pub fn flush(&mut self) -> usize {
+        let Some(ref mut queue) = self.queue else { return 0 };
+        let queued = queue.len();
+        for deliver in queue.drain(..) {
+            deliver(&mut self.topics);
+        }
+        queued
+    }
+
+    /// Check if the bus holds events until they are flushed.
+    pub fn is_queued(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// Get the number of events waiting to be flushed.
+    pub fn pending(&self) -> usize {
+        self.queue.as_ref().map_or(0, VecDeque::len)
+    }
+}
+
+fn deliver<E: Any>(topics: &mut TypeMap<dyn Any + Send>, event: &E) {
+    for (_, handler) in topics.get_mut::<Topic<E>>().into_iter().flatten() {
+        handler(event);
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> EventBus {
+        EventBus::new()
+    }
+}
+
+impl fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventBus")
+            .field("topics", &self.topics.len())
+            .field("queued", &self.is_queued())
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+impl<E> Clone for Subscription<E> {
+    fn clone(&self) -> Subscription<E> {
+        *self
+    }
+}
+
+impl<E> Copy for Subscription<E> {}
+
+impl<E> PartialEq for Subscription<E> {
+    fn eq(&self, other: &Subscription<E>) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<E> Eq for Subscription<E> {}
+
+impl<E> fmt::Debug for Subscription<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscription").field("event", &type_name::<E>()).field("id", &self.id).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+
+    use super::EventBus;
+
+    struct Login(&'static str);
+
+    struct Logout(&'static str);
+
+    #[test] fn test_publish_and_unsubscribe() {
+        let (tx, rx) = mpsc::channel();
+        let mut bus = EventBus::new();
+        let audit = tx.clone();
+        bus.subscribe(move |login: &Login| audit.send(("audit", login.0)).unwrap());
+        let greet = tx.clone();
+        let greeter = bus.subscribe(move |login: &Login| greet.send(("greet", login.0)).unwrap());
+        bus.subscribe(move |logout: &Logout| tx.send(("bye", logout.0)).unwrap());
+        assert_eq!(bus.subscribers::<Login>(), 2);
+
+        bus.publish(Login("ann"));
+        assert!(bus.unsubscribe(greeter));
+        assert!(!bus.unsubscribe(greeter));
+        bus.publish(Logout("ann"));
+        bus.publish(Login("bob"));
+        bus.publish(42u32);
+        assert_eq!(bus.flush(), 0);
+
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(),
+                   [("audit", "ann"), ("greet", "ann"), ("bye", "ann"), ("audit", "bob")]);
+        assert_eq!(bus.subscribers::<Login>(), 1);
+        assert_eq!(bus.subscribers::<u32>(), 0);
+    }
+
+    #[test] fn test_queued() {
+        let (tx, rx) = mpsc::channel();
+        let mut bus = EventBus::queued();
+        let login = tx.clone();
+        bus.subscribe(move |event: &Login| login.send(event.0).unwrap());
+        bus.subscribe(move |event: &Logout| tx.send(event.0).unwrap());
+
+        bus.publish(Login("ann"));
+        bus.publish(Logout("bob"));
+        bus.publish(Login("cat"));
+        assert_eq!(rx.try_recv().ok(), None);
+        assert_eq!(bus.pending(), 3);
+
+        assert_eq!(bus.flush(), 3);
+        assert_eq!(bus.pending(), 0);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), ["ann", "bob", "cat"]);
+    }
+}
@@ -0,0 +1,233 @@
+//! A TypeMap which can be shared and mutated across threads without
+//! a single global lock.
+
+use std::any::TypeId;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::{BuildTypeIdHasher, Key, SyncTypeMap};
+
+const DEFAULT_SHARDS: usize = 16;
+
+/// A concurrent map keyed by types.
+///
+/// Entries are spread over a number of independently locked shards, so
+/// accesses to different keys rarely contend. All operations take `&self`.
+///
+/// Values must be `Send + Sync`, as they are shared between threads.
+///
+/// Lock poisoning is ignored: a panic while holding a guard leaves the
+/// value as the panicking code left it.
+pub struct ConcurrentTypeMap {
+    shards: Box<[RwLock<SyncTypeMap>]>
+}
+
+/// A read guard for a value in a ConcurrentTypeMap.
+///
+/// Holds a shared lock on the value's shard until dropped.
+pub struct ReadGuard<'a, V> {
+    _guard: RwLockReadGuard<'a, SyncTypeMap>,
+    value: NonNull<V>
+}
+
+/// A write guard for a value in a ConcurrentTypeMap.
+///
+/// Holds an exclusive lock on the value's shard until dropped.
+pub struct WriteGuard<'a, V> {
+    _guard: RwLockWriteGuard<'a, SyncTypeMap>,
+    value: NonNull<V>,
+    phantom: PhantomData<&'a mut V>
+}
+
+impl ConcurrentTypeMap {
+    /// Create a new, empty ConcurrentTypeMap.
+    pub fn new() -> ConcurrentTypeMap {
+        ConcurrentTypeMap::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Create a new, empty ConcurrentTypeMap with a specific number of shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero.
+    pub fn with_shards(shards: usize) -> ConcurrentTypeMap {
+        assert!(shards > 0, "ConcurrentTypeMap needs at least one shard");
+        ConcurrentTypeMap {
+            shards: (0..shards).map(|_| RwLock::new(SyncTypeMap::custom())).collect()
+        }
+    }
+
+    /// Insert a value into the map with a specified key type.
+    ///
+    /// Returns the value previously stored for the key, if any.
+    pub fn insert<K: Key>(&self, val: K::Value) -> Option<K::Value>
+    where K::Value: Send + Sync {
+        self.write::<K>().insert::<K>(val)
+    }
+
+    /// Find a value in the map and lock it for reading.
+    pub fn get<K: Key>(&self) -> Option<ReadGuard<'_, K::Value>> {
+        let guard = self.read::<K>();
+        let value = NonNull::from(guard.get::<K>()?);
+        Some(ReadGuard { _guard: guard, value })
+    }
+
+    /// Find a value in the map and lock it for writing.
+    pub fn get_mut<K: Key>(&self) -> Option<WriteGuard<'_, K::Value>> {
+        let mut guard = self.write::<K>();
+        let value = NonNull::from(guard.get_mut::<K>()?);
+        Some(WriteGuard { _guard: guard, value, phantom: PhantomData })
+    }
+
+    /// Check if a key has an associated value stored in the map.
+    pub fn contains<K: Key>(&self) -> bool {
+        self.read::<K>().contains::<K>()
+    }
+
+    /// Remove a value from the map.
+    ///
+    /// Returns the removed value, if there was one.
+    pub fn remove<K: Key>(&self) -> Option<K::Value> {
+        self.write::<K>().remove::<K>()
+    }
+
+    /// Get the number of values stored in the map.
+    ///
+    /// Shards are counted one at a time, so the result may be stale if the
+    /// map is modified concurrently.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| lock_read(shard).len()).sum()
+    }
+
+    /// Return true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| lock_read(shard).is_empty())
+    }
+
+    /// Remove all entries from the map.
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            lock_write(shard).clear();
+        }
+    }
+
+    fn shard<K: 'static>(&self) -> &RwLock<SyncTypeMap> {
+        let hash = BuildTypeIdHasher::default().hash_one(TypeId::of::<K>());
+        // The low bits pick buckets within each shard, so pick the shard
+        // with the high bits.
+        &self.shards[(hash >> 32) as usize % self.shards.len()]
+    }
+
+    fn read<K: 'static>(&self) -> RwLockReadGuard<'_, SyncTypeMap> {
+        lock_read(self.shard::<K>())
+    }
+
+    fn write<K: 'static>(&self) -> RwLockWriteGuard<'_, SyncTypeMap> {
+        lock_write(self.shard::<K>())
+    }
+}
+
+impl Default for ConcurrentTypeMap {
+    fn default() -> ConcurrentTypeMap {
+        ConcurrentTypeMap::new()
+    }
+}
+
+fn lock_read(shard: &RwLock<SyncTypeMap>) -> RwLockReadGuard<'_, SyncTypeMap> {
+    shard.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn lock_write(shard: &RwLock<SyncTypeMap>) -> RwLockWriteGuard<'_, SyncTypeMap> {
+    shard.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl<'a, V> Deref for ReadGuard<'a, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        // The value is stored in the shard's map, either boxed or inline,
+        // and the map cannot be written or rehashed while the read guard
+        // is held, so the value does not move.
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<'a, V: fmt::Debug> fmt::Debug for ReadGuard<'a, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl<'a, V> Deref for WriteGuard<'a, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        // The value is stored in the shard's map, either boxed or inline,
+        // and the map is exclusively locked by the write guard, which
+        // never inserts or removes entries, so the value does not move.
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<'a, V> DerefMut for WriteGuard<'a, V> {
+    fn deref_mut(&mut self) -> &mut V {
+        unsafe { self.value.as_mut() }
+    }
+}
+
+impl<'a, V: fmt::Debug> fmt::Debug for WriteGuard<'a, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::ConcurrentTypeMap;
+    use crate::Key;
+
+    struct Hits;
+
+    impl Key for Hits { type Value = usize; }
+
+    struct Name;
+
+    impl Key for Name { type Value = String; }
+
+    #[test] fn test_insert_get_remove() {
+        let map = ConcurrentTypeMap::new();
+        assert_eq!(map.insert::<Hits>(1), None);
+        assert_eq!(map.insert::<Name>("typemap".to_string()), None);
+        assert_eq!(*map.get::<Hits>().unwrap(), 1);
+        assert_eq!(&*map.get::<Name>().unwrap(), "typemap");
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.remove::<Hits>(), Some(1));
+        assert!(!map.contains::<Hits>());
+        map.clear();
+        assert!(map.is_empty());
+    }
+
+    #[test] fn test_get_mut_from_many_threads() {
+        let map = Arc::new(ConcurrentTypeMap::with_shards(4));
+        map.insert::<Hits>(0);
+        let workers: Vec<_> = (0..8).map(|_| {
+            let map = map.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    *map.get_mut::<Hits>().unwrap() += 1;
+                }
+            })
+        }).collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(*map.get::<Hits>().unwrap(), 800);
+    }
+}
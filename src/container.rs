@@ -0,0 +1,307 @@
+//! A dependency injection container, which builds the value of each key
+//! type from a registered provider.
+
+use std::any::{type_name, Any, TypeId};
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{Arc, OnceLock};
+
+use crate::{Key, SyncTypeMap, TypeMap};
+
+/// How often a provider is called to build its key's value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lifetime {
+    /// Once per container; every resolution shares the value.
+    Singleton,
+    /// Once per resolution.
+    Transient,
+    /// Once per `Scope`; resolutions within a scope share the value.
+    Scoped
+}
+
+/// An error resolving a value from a Container.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ContainerError {
+    /// No provider is registered for the key type.
+    Unregistered {
+        /// The name of the key type.
+        key: &'static str
+    },
+    /// A scoped key type was resolved outside of a scope, either directly
+    /// from the container or as a dependency of a singleton.
+    NoScope {
+        /// The name of the key type.
+        key: &'static str
+    },
+    /// A key type depends on itself.
+    Cycle {
+        /// The names of the key types being resolved, from the first to
+        /// the one which was resolved again.
+        chain: Vec<&'static str>
+    }
+}
+
+/// A container of providers, each building the value of a key type.
+///
+/// Providers are registered with a `Lifetime`, and resolve their own
+/// dependencies through the `Resolver` they are called with. Values are
+/// shared through an `Arc`, so they must be `Send + Sync`.
+pub struct Container {
+    providers: SyncTypeMap
+}
+
+/// A scope within a Container, which shares the values of scoped key
+/// types between resolutions, such as for a single request.
+pub struct Scope<'c> {
+    container: &'c Container,
+    instances: RefCell<TypeMap<dyn Any + Send>>
+}
+
+/// Resolves the dependencies of a provider while it builds its value.
+pub struct Resolver<'a> {
+    container: &'a Container,
+    scope: Option<&'a Scope<'a>>,
+    parent: Option<&'a Resolver<'a>>,
+    id: TypeId,
+    key: &'static str
+}
+
+type BuildFn<V> = Box<dyn Fn(&Resolver<'_>) -> Result<V, ContainerError> + Send + Sync>;
+
+struct Provider<K: Key> {
+    lifetime: Lifetime,
+    build: BuildFn<K::Value>,
+    singleton: OnceLock<Arc<K::Value>>
+}
+
+struct Provide<K>(PhantomData<K>);
+
+impl<K: Key> Key for Provide<K> where K::Value: Send + Sync { type Value = Provider<K>; }
+
+struct Instance<K>(PhantomData<K>);
+
+impl<K: Key> Key for Instance<K> where K::Value: Send + Sync { type Value = Arc<K::Value>; }
+
+impl Container {
+    /// Create a new Container with no providers.
+    pub fn new() -> Container {
+        Container { providers: SyncTypeMap::custom() }
+    }
+
+    /// Register a provider for a key type, replacing any previous one.
+    pub fn register<K: Key, F>(&mut self, lifetime: Lifetime, build: F)
+    where K::Value: Send + Sync, F: Fn(&Resolver<'_>) -> Result<K::Value, ContainerError> + Send + Sync + 'static {
+        self.providers.insert::<Provide<K>>(Provider {
+            lifetime,
+            build: Box::new(build),
+            singleton: OnceLock::new()
+        });
+    }
+
+    /// Register a provider which is called once, the first time its key
+    /// type is resolved.
+    ///
+    /// Threads which resolve the key type at the same time may each call
+    /// the provider, but all of them share the first value stored.
+    pub fn singleton<K: Key, F>(&mut self, build: F)
+    where K::Value: Send + Sync, F: Fn(&Resolver<'_>) -> Result<K::Value, ContainerError> + Send + Sync + 'static {
+        self.register::<K, F>(Lifetime::Singleton, build)
+    }
+
+    /// Register a provider which is called every time its key type is
+    /// resolved.
+    pub fn transient<K: Key, F>(&mut self, build: F)
+    where K::Value: Send + Sync, F: Fn(&Resolver<'_>) -> Result<K::Value, ContainerError> + Send + Sync + 'static {
+        self.register::<K, F>(Lifetime::Transient, build)
+    }
+
+    /// Register a provider which is called once in each scope its key type
+    /// is resolved in.
+    pub fn scoped<K: Key, F>(&mut self, build: F)
+    where K::Value: Send + Sync, F: Fn(&Resolver<'_>) -> Result<K::Value, ContainerError> + Send + Sync + 'static {
+        self.register::<K, F>(Lifetime::Scoped, build)
+    }
+
+    /// Check if a provider is registered for a key type.
+    pub fn contains<K: Key>(&self) -> bool where K::Value: Send + Sync {
+        self.providers.contains::<Provide<K>>()
+    }
+
+    /// Resolve the value of a key type outside of any scope.
+    pub fn resolve<K: Key>(&self) -> Result<Arc<K::Value>, ContainerError> where K::Value: Send + Sync {
+        resolve::<K>(self, None, None)
+    }
+
+    /// Create a new scope, with no scoped values yet.
+    pub fn scope(&self) -> Scope<'_> {
+        Scope { container: self, instances: RefCell::new(TypeMap::custom()) }
+    }
+}
+
+impl<'c> Scope<'c> {
+    /// Resolve the value of a key type within this scope.
+    pub fn resolve<K: Key>(&self) -> Result<Arc<K::Value>, ContainerError> where K::Value: Send + Sync {
+        resolve::<K>(self.container, Some(self), None)
+    }
+}
+
+impl<'a> Resolver<'a> {
+    /// Resolve the value of a dependency.
+    ///
+    /// The dependency is resolved in the same scope as the value being
+    /// built, except for the dependencies of singletons, which are
+    /// resolved outside of any scope.
+    pub fn resolve<K: Key>(&self) -> Result<Arc<K::Value>, ContainerError> where K::Value: Send + Sync {
+        resolve::<K>(self.container, self.scope, Some(self))
+    }
+
+    fn resolving(&self, id: TypeId) -> bool {
+        self.id == id || self.parent.is_some_and(|parent| parent.resolving(id))
+    }
+}
+
+fn resolve<K: Key>(container: &Container, scope: Option<&Scope<'_>>, parent: Option<&Resolver<'_>>)
+                   -> Result<Arc<K::Value>, ContainerError>
+where K::Value: Send + Sync {
+    let id = TypeId::of::<K>();
+    let key = type_name::<K>();
+    if parent.is_some_and(|parent| parent.resolving(id)) {
+        let mut chain = vec![key];
+        let mut frame = parent;
+        while let Some(resolver) = frame {
+            chain.push(resolver.key);
+            frame = resolver.parent;
+        }
+        chain.reverse();
+        return Err(ContainerError::Cycle { chain });
+    }
+
+    let provider = container.providers.get::<Provide<K>>().ok_or(ContainerError::Unregistered { key })?;
+    let resolver = |scope| Resolver { container, scope, parent, id, key };
+    match provider.lifetime {
+        Lifetime::Transient => Ok(Arc::new((provider.build)(&resolver(scope))?)),
+        Lifetime::Singleton => {
+            if let Some(val) = provider.singleton.get() { return Ok(val.clone()) }
+            let val = Arc::new((provider.build)(&resolver(None))?);
+            Ok(provider.singleton.get_or_init(|| val).clone())
+        },
+        Lifetime::Scoped => {
+            let scope = scope.ok_or(ContainerError::NoScope { key })?;
+            if let Some(val) = scope.instances.borrow().get::<Instance<K>>() { return Ok(val.clone()) }
+            let val = Arc::new((provider.build)(&resolver(Some(scope)))?);
+            Ok(scope.instances.borrow_mut().entry::<Instance<K>>().or_insert(val).clone())
+        }
+    }
+}
+
+impl Default for Container {
+    fn default() -> Container {
+        Container::new()
+    }
+}
+
+impl fmt::Debug for Container {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Container").field("providers", &self.providers.len()).finish()
+    }
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ContainerError::Unregistered { key } =>
+                write!(f, "no provider registered for `{}`", key),
+            ContainerError::NoScope { key } =>
+                write!(f, "scoped `{}` resolved outside of a scope", key),
+            ContainerError::Cycle { ref chain } =>
+                write!(f, "cyclic dependency: {}", chain.join(" -> "))
+        }
+    }
+}
+
+impl Error for ContainerError {}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::Key;
+
+    use super::{Container, ContainerError};
+
+    struct Config;
+
+    impl Key for Config { type Value = String; }
+
+    struct DbPool;
+
+    impl Key for DbPool { type Value = (String, usize); }
+
+    struct Request;
+
+    impl Key for Request { type Value = (Arc<(String, usize)>, usize); }
+
+    static BUILT: AtomicUsize = AtomicUsize::new(0);
+
+    fn container() -> Container {
+        let mut container = Container::new();
+        container.singleton::<Config, _>(|_| Ok("postgres://".to_string()));
+        container.singleton::<DbPool, _>(|deps| {
+            Ok((deps.resolve::<Config>()?.to_string(), BUILT.fetch_add(1, Ordering::SeqCst)))
+        });
+        container.scoped::<Request, _>(|deps| {
+            Ok((deps.resolve::<DbPool>()?, BUILT.fetch_add(1, Ordering::SeqCst)))
+        });
+        container
+    }
+
+    #[test] fn test_lifetimes() {
+        let container = container();
+        let pool = container.resolve::<DbPool>().unwrap();
+        assert_eq!(pool.0, "postgres://");
+        assert!(Arc::ptr_eq(&pool, &container.resolve::<DbPool>().unwrap()));
+
+        assert_eq!(container.resolve::<Request>().err(),
+                   Some(ContainerError::NoScope { key: "typemap::container::test::Request" }));
+
+        let (first, second) = (container.scope(), container.scope());
+        let request = first.resolve::<Request>().unwrap();
+        assert!(Arc::ptr_eq(&request, &first.resolve::<Request>().unwrap()));
+        assert!(!Arc::ptr_eq(&request, &second.resolve::<Request>().unwrap()));
+        assert!(Arc::ptr_eq(&request.0, &pool));
+
+        let mut container = container;
+        container.transient::<Config, _>(|_| Ok("fresh".to_string()));
+        let config = container.resolve::<Config>().unwrap();
+        assert!(!Arc::ptr_eq(&config, &container.resolve::<Config>().unwrap()));
+    }
+
+    struct A;
+
+    impl Key for A { type Value = (); }
+
+    struct B;
+
+    impl Key for B { type Value = (); }
+
+    struct C;
+
+    impl Key for C { type Value = (); }
+
+    #[test] fn test_cycles_and_unregistered() {
+        let mut container = Container::new();
+        container.singleton::<A, _>(|deps| deps.resolve::<B>().map(|_| ()));
+        container.transient::<B, _>(|deps| deps.resolve::<A>().map(|_| ()));
+        container.transient::<C, _>(|deps| deps.resolve::<Config>().map(|_| ()));
+
+        let cycle = container.resolve::<A>().unwrap_err();
+        assert_eq!(cycle.to_string(),
+                   "cyclic dependency: typemap::container::test::A -> typemap::container::test::B -> typemap::container::test::A");
+        assert_eq!(container.resolve::<C>().err(),
+                   Some(ContainerError::Unregistered { key: "typemap::container::test::Config" }));
+        assert!(container.contains::<C>());
+    }
+}
@@ -0,0 +1,42 @@
+use core::error::Error;
+use core::fmt;
+
+/// An error accessing a value in a TypeMap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TypeMapError {
+    /// There is no value for the key type.
+    Missing {
+        /// The name of the key type.
+        key: &'static str
+    },
+    /// The value for the key type is not of the expected type.
+    TypeMismatch {
+        /// The name of the key type.
+        key: &'static str,
+        /// The name of the value type which was asked for.
+        expected: &'static str,
+        /// The name of the value type which is stored.
+        found: &'static str
+    },
+    /// The same key type was given more than once where distinct key types
+    /// are needed.
+    DuplicateKey {
+        /// The name of the key type.
+        key: &'static str
+    }
+}
+
+impl fmt::Display for TypeMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            TypeMapError::Missing { key } =>
+                write!(f, "no value for TypeMap key `{}`", key),
+            TypeMapError::TypeMismatch { key, expected, found } =>
+                write!(f, "TypeMap key `{}` holds a `{}`, not a `{}`", key, found, expected),
+            TypeMapError::DuplicateKey { key } =>
+                write!(f, "TypeMap key `{}` given more than once", key)
+        }
+    }
+}
+
+impl Error for TypeMapError {}
@@ -0,0 +1,50 @@
+use core::hash::{BuildHasherDefault, Hasher};
+
+/// A hasher for `TypeId`s, which passes their hash through unchanged.
+///
+/// A `TypeId` is already a well distributed hash of its type, so hashing
+/// it again with a general purpose hasher is wasted work.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TypeIdHasher {
+    value: u64
+}
+
+/// The default `BuildHasher` of a TypeMap.
+pub type BuildTypeIdHasher = BuildHasherDefault<TypeIdHasher>;
+
+impl Hasher for TypeIdHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        // TypeId only writes a single u64, but fold in anything else in
+        // case that ever changes.
+        for &byte in bytes {
+            self.value = (self.value.rotate_left(8) ^ u64::from(byte)).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.value ^= value;
+    }
+
+    fn finish(&self) -> u64 {
+        self.value
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::any::TypeId;
+    use std::hash::{BuildHasher, Hasher};
+
+    use super::{BuildTypeIdHasher, TypeIdHasher};
+
+    #[test] fn test_passes_type_id_hash_through() {
+        let mut hasher = TypeIdHasher::default();
+        hasher.write_u64(0xdead_beef);
+        assert_eq!(hasher.finish(), 0xdead_beef);
+    }
+
+    #[test] fn test_distinguishes_type_ids() {
+        let build = BuildTypeIdHasher::default();
+        assert_ne!(build.hash_one(TypeId::of::<u8>()), build.hash_one(TypeId::of::<u16>()));
+    }
+}
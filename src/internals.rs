@@ -0,0 +1,403 @@
+use alloc::boxed::Box;
+use core::any::{type_name, Any};
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::{self, ManuallyDrop, MaybeUninit};
+use core::ptr;
+
+/// A trait object type which can be used as the value storage of a TypeMap.
+///
+/// Implemented for `dyn Any` and `dyn CloneAny`, alone or with `+ Send`
+/// and `+ Send + Sync`.
+pub trait Storage: 'static {
+    /// View the stored value as `Any`.
+    fn as_any(&self) -> &dyn Any;
+
+    /// View the stored value as mutable `Any`.
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    /// Convert the boxed value into a boxed `Any`.
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+/// Values which can be stored in a TypeMap using the storage type `A`.
+///
+/// This is how the bounds of a storage type, such as `Send`, are imposed
+/// on the values inserted into a TypeMap. Values of a custom storage type
+/// are always boxed; only the storage types of this crate store small
+/// values inline.
+pub trait Implements<A: ?Sized + Storage> {
+    /// Box the value as the storage trait object.
+    fn into_object(self) -> Box<A>;
+
+    // `Inline` cannot be named outside of this crate, so only the impls
+    // below, whose bounds prove that the value implements `A`, can store
+    // a value inline.
+    #[doc(hidden)]
+    fn into_inline(self) -> Result<Inline<A>, Self> where Self: Sized { Err(self) }
+}
+
+/// `Any` for values which can be cloned through a trait object.
+///
+/// This trait is sealed: it is implemented for every `Clone` type, and
+/// cannot be implemented otherwise.
+pub trait CloneAny: Any + private::Sealed {
+    #[doc(hidden)]
+    fn clone_any(&self) -> Box<dyn CloneAny>;
+
+    #[doc(hidden)]
+    fn clone_any_send(&self) -> Box<dyn CloneAny + Send> where Self: Send;
+
+    #[doc(hidden)]
+    fn clone_any_sync(&self) -> Box<dyn CloneAny + Send + Sync> where Self: Send + Sync;
+
+    #[doc(hidden)]
+    fn clone_inline() -> CloneFn where Self: Sized;
+}
+
+mod private {
+    pub trait Sealed {}
+
+    impl<T: Clone> Sealed for T {}
+}
+
+impl<T: Any + Clone> CloneAny for T {
+    fn clone_any(&self) -> Box<dyn CloneAny> { Box::new(self.clone()) }
+
+    fn clone_any_send(&self) -> Box<dyn CloneAny + Send> where Self: Send {
+        Box::new(self.clone())
+    }
+
+    fn clone_any_sync(&self) -> Box<dyn CloneAny + Send + Sync> where Self: Send + Sync {
+        Box::new(self.clone())
+    }
+
+    fn clone_inline() -> CloneFn { clone_inline::<T> }
+}
+
+impl Clone for Box<dyn CloneAny> {
+    fn clone(&self) -> Box<dyn CloneAny> { (**self).clone_any() }
+}
+
+impl Clone for Box<dyn CloneAny + Send> {
+    fn clone(&self) -> Box<dyn CloneAny + Send> { (**self).clone_any_send() }
+}
+
+impl Clone for Box<dyn CloneAny + Send + Sync> {
+    fn clone(&self) -> Box<dyn CloneAny + Send + Sync> { (**self).clone_any_sync() }
+}
+
+macro_rules! impl_storage {
+    ($object:ident: $($bound:path),*) => {
+        impl_storage!($object: $($bound),*; None);
+    };
+    ($object:ident: $($bound:path),*; $clone:expr) => {
+        impl Storage for dyn $object $(+ $bound)* {
+            fn as_any(&self) -> &dyn Any { self }
+            fn as_any_mut(&mut self) -> &mut dyn Any { self }
+            fn into_any(self: Box<Self>) -> Box<dyn Any> { self }
+        }
+
+        impl<T: $object $(+ $bound)*> Implements<dyn $object $(+ $bound)*> for T {
+            fn into_object(self) -> Box<dyn $object $(+ $bound)*> { Box::new(self) }
+            fn into_inline(self) -> Result<Inline<dyn $object $(+ $bound)*>, T> {
+                Inline::new(self, $clone)
+            }
+        }
+    }
+}
+
+impl_storage!(Any:);
+impl_storage!(Any: Send);
+impl_storage!(Any: Send, Sync);
+impl_storage!(CloneAny:; Some(T::clone_inline()));
+impl_storage!(CloneAny: Send; Some(T::clone_inline()));
+impl_storage!(CloneAny: Send, Sync; Some(T::clone_inline()));
+
+type DebugFn = fn(&dyn Any, &mut fmt::Formatter<'_>) -> fmt::Result;
+
+/// Space for a value stored inline, without boxing it.
+type Words = MaybeUninit<[usize; 3]>;
+
+#[doc(hidden)]
+pub type CloneFn = unsafe fn(*const Words) -> Words;
+
+/// A value stored in a TypeMap, along with the names of its key and value
+/// types for debugging.
+pub(crate) struct Slot<A: ?Sized> {
+    pub(crate) key: &'static str,
+    pub(crate) value: &'static str,
+    debug: Option<DebugFn>,
+    object: Object<A>
+}
+
+/// A value which is either boxed as the storage trait object or, if it
+/// fits in `Words`, stored in place.
+enum Object<A: ?Sized> {
+    Boxed(Box<A>),
+    Inline(Inline<A>)
+}
+
+/// A value stored in place, along with the functions which take the place
+/// of the storage trait object's vtable.
+///
+/// The words are in an `UnsafeCell`, as a value with interior mutability
+/// may be mutated through a shared reference to the slot.
+#[doc(hidden)]
+pub struct Inline<A: ?Sized> {
+    words: UnsafeCell<Words>,
+    as_any: unsafe fn(*mut Words) -> *mut dyn Any,
+    drop: unsafe fn(*mut Words),
+    clone: Option<CloneFn>,
+    // Only values implementing `A` are stored, so take on its auto traits.
+    phantom: PhantomData<Box<A>>
+}
+
+impl<A: ?Sized + Storage> Slot<A> {
+    pub(crate) fn new<K: 'static, V: Implements<A> + 'static>(val: V) -> Slot<A> {
+        Slot {
+            key: type_name::<K>(),
+            value: type_name::<V>(),
+            debug: None,
+            object: Object::new(val)
+        }
+    }
+
+    pub(crate) fn with_debug<K, V>(val: V) -> Slot<A>
+    where K: 'static, V: Implements<A> + fmt::Debug + 'static {
+        Slot { debug: Some(debug_value::<V>), ..Slot::new::<K, V>(val) }
+    }
+
+    pub(crate) fn as_any(&self) -> &dyn Any {
+        match self.object {
+            Object::Boxed(ref object) => object.as_any(),
+            Object::Inline(ref inline) => unsafe { &*(inline.as_any)(inline.words.get()) }
+        }
+    }
+
+    pub(crate) fn as_any_mut(&mut self) -> &mut dyn Any {
+        match self.object {
+            Object::Boxed(ref mut object) => object.as_any_mut(),
+            Object::Inline(ref mut inline) => unsafe { &mut *(inline.as_any)(inline.words.get_mut()) }
+        }
+    }
+
+    /// Take the value out, if it is a `V`.
+    pub(crate) fn downcast<V: 'static>(self) -> Result<V, Slot<A>> {
+        if !self.as_any().is::<V>() { return Err(self) }
+        match self.object {
+            Object::Boxed(object) => match object.into_any().downcast() {
+                Ok(val) => Ok(*val),
+                Err(_) => unreachable!()
+            },
+            Object::Inline(inline) => {
+                let inline = ManuallyDrop::new(inline);
+                Ok(unsafe { ptr::read(inline.words.get() as *const V) })
+            }
+        }
+    }
+}
+
+impl<A: ?Sized + Storage> Object<A> {
+    fn new<V: Implements<A> + 'static>(val: V) -> Object<A> {
+        match val.into_inline() {
+            Ok(inline) => Object::Inline(inline),
+            Err(val) => Object::Boxed(val.into_object())
+        }
+    }
+}
+
+impl<A: ?Sized> Inline<A> {
+    /// Store a value in place, if it fits in `Words`.
+    ///
+    /// Only called where `V` is known to implement `A`, and with a clone
+    /// function for `V` if any.
+    fn new<V: Any>(val: V, clone: Option<CloneFn>) -> Result<Inline<A>, V> {
+        if mem::size_of::<V>() > mem::size_of::<Words>() || mem::align_of::<V>() > mem::align_of::<Words>() {
+            return Err(val);
+        }
+
+        let mut words = Words::uninit();
+        unsafe { ptr::write(words.as_mut_ptr() as *mut V, val) };
+        Ok(Inline {
+            words: UnsafeCell::new(words),
+            as_any: inline_as_any::<V>,
+            drop: inline_drop::<V>,
+            clone,
+            phantom: PhantomData
+        })
+    }
+}
+
+// The `UnsafeCell` opts out of the auto traits, so restore those of `A`,
+// which every value stored in an `Inline<A>` implements.
+unsafe impl<A: ?Sized> Send for Inline<A> where Box<A>: Send {}
+unsafe impl<A: ?Sized> Sync for Inline<A> where Box<A>: Sync {}
+
+impl<A: ?Sized> Drop for Inline<A> {
+    fn drop(&mut self) {
+        unsafe { (self.drop)(self.words.get_mut()) }
+    }
+}
+
+unsafe fn inline_as_any<V: Any>(words: *mut Words) -> *mut dyn Any {
+    words as *mut V
+}
+
+unsafe fn inline_drop<V>(words: *mut Words) {
+    ptr::drop_in_place(words as *mut V)
+}
+
+unsafe fn clone_inline<V: Clone>(words: *const Words) -> Words {
+    let mut clone = Words::uninit();
+    ptr::write(clone.as_mut_ptr() as *mut V, (*(words as *const V)).clone());
+    clone
+}
+
+impl<A: ?Sized> Clone for Object<A> where Box<A>: Clone {
+    fn clone(&self) -> Object<A> {
+        match *self {
+            Object::Boxed(ref object) => Object::Boxed(object.clone()),
+            Object::Inline(ref inline) => {
+                let clone = inline.clone.expect("TypeMap storage type is Clone, but its values are not");
+                Object::Inline(Inline {
+                    words: UnsafeCell::new(unsafe { clone(inline.words.get()) }),
+                    clone: inline.clone,
+                    ..*inline
+                })
+            }
+        }
+    }
+}
+
+impl<A: ?Sized> Clone for Slot<A> where Box<A>: Clone {
+    fn clone(&self) -> Slot<A> {
+        Slot {
+            key: self.key,
+            value: self.value,
+            debug: self.debug,
+            object: self.object.clone()
+        }
+    }
+}
+
+impl<A: ?Sized + Storage> fmt::Debug for Slot<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.value)?;
+        if let Some(debug) = self.debug {
+            f.write_str(" = ")?;
+            debug(self.as_any(), f)?;
+        }
+        Ok(())
+    }
+}
+
+fn debug_value<V: fmt::Debug + 'static>(val: &dyn Any, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match val.downcast_ref::<V>() {
+        Some(val) => val.fmt(f),
+        None => f.write_str("<unknown>")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::any::Any;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    use super::{CloneAny, Implements, Object, Slot, Storage};
+
+    struct Key;
+
+    fn is_inline<A: ?Sized>(slot: &Slot<A>) -> bool {
+        matches!(slot.object, Object::Inline(_))
+    }
+
+    #[test] fn test_small_values_are_inline() {
+        let slot = Slot::<dyn Any>::new::<Key, u32>(7);
+        assert!(is_inline(&slot));
+        assert_eq!(slot.as_any().downcast_ref::<u32>(), Some(&7));
+
+        let slot = Slot::<dyn Any>::new::<Key, [usize; 4]>([1; 4]);
+        assert!(!is_inline(&slot));
+        assert_eq!(slot.downcast::<[usize; 4]>().ok(), Some([1; 4]));
+
+        let slot = Slot::<dyn Any>::new::<Key, u128>(1);
+        assert_eq!(is_inline(&slot), std::mem::align_of::<u128>() <= std::mem::align_of::<usize>());
+    }
+
+    #[test] fn test_inline_values_are_dropped_once() {
+        let rc = Rc::new(());
+        let mut slot = Slot::<dyn Any>::new::<Key, Rc<()>>(rc.clone());
+        assert!(is_inline(&slot));
+        assert_eq!(Rc::strong_count(&rc), 2);
+
+        *slot.as_any_mut().downcast_mut::<Rc<()>>().unwrap() = rc.clone();
+        assert_eq!(Rc::strong_count(&rc), 2);
+
+        let slot = match slot.downcast::<String>() {
+            Ok(_) => panic!("downcast to the wrong type"),
+            Err(slot) => slot
+        };
+        let taken = slot.downcast::<Rc<()>>().ok().unwrap();
+        assert_eq!(Rc::strong_count(&rc), 2);
+        drop(taken);
+        assert_eq!(Rc::strong_count(&rc), 1);
+
+        drop(Slot::<dyn Any>::new::<Key, Rc<()>>(rc.clone()));
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    #[test] fn test_clone_inline_values() {
+        let rc = Rc::new(5);
+        let slot = Slot::<dyn CloneAny>::new::<Key, Rc<usize>>(rc.clone());
+        let clone = slot.clone();
+        assert!(is_inline(&clone));
+        assert_eq!(Rc::strong_count(&rc), 3);
+        assert_eq!(**clone.as_any().downcast_ref::<Rc<usize>>().unwrap(), 5);
+        drop((slot, clone));
+        assert_eq!(Rc::strong_count(&rc), 1);
+    }
+
+    #[test] fn test_inline_interior_mutability() {
+        let slot = Slot::<dyn CloneAny>::new::<Key, RefCell<usize>>(RefCell::new(1));
+        assert!(is_inline(&slot));
+        *slot.as_any().downcast_ref::<RefCell<usize>>().unwrap().borrow_mut() += 1;
+        let clone = slot.clone();
+        assert_eq!(*clone.as_any().downcast_ref::<RefCell<usize>>().unwrap().borrow(), 2);
+
+        let slot = Slot::<dyn Any>::new::<Key, Cell<u8>>(Cell::new(1));
+        slot.as_any().downcast_ref::<Cell<u8>>().unwrap().set(3);
+        assert_eq!(slot.downcast::<Cell<u8>>().ok().unwrap().get(), 3);
+    }
+
+    trait Shape: Any {
+        fn clone_shape(&self) -> Box<dyn Shape>;
+    }
+
+    impl<T: Any + Clone> Shape for T {
+        fn clone_shape(&self) -> Box<dyn Shape> { Box::new(self.clone()) }
+    }
+
+    impl Storage for dyn Shape {
+        fn as_any(&self) -> &dyn Any { self }
+        fn as_any_mut(&mut self) -> &mut dyn Any { self }
+        fn into_any(self: Box<Self>) -> Box<dyn Any> { self }
+    }
+
+    impl<T: Shape> Implements<dyn Shape> for T {
+        fn into_object(self) -> Box<dyn Shape> { Box::new(self) }
+    }
+
+    impl Clone for Box<dyn Shape> {
+        fn clone(&self) -> Box<dyn Shape> { (**self).clone_shape() }
+    }
+
+    #[test] fn test_custom_storage_is_boxed() {
+        let slot = Slot::<dyn Shape>::new::<Key, u32>(7);
+        assert!(!is_inline(&slot));
+        let clone = slot.clone();
+        assert_eq!(clone.as_any().downcast_ref::<u32>(), Some(&7));
+    }
+}
@@ -0,0 +1,309 @@
+//! Iteration over the type-erased entries of a TypeMap.
+
+use core::any::{Any, TypeId};
+use core::fmt;
+
+use crate::internals::Slot;
+use crate::store;
+use crate::{Key, Storage, TypeMap};
+
+/// A shared reference to a type-erased entry of a TypeMap.
+pub struct ErasedRef<'a, A: ?Sized + Storage = dyn Any> {
+    id: TypeId,
+    slot: &'a Slot<A>
+}
+
+/// A mutable reference to a type-erased entry of a TypeMap.
+pub struct ErasedMut<'a, A: ?Sized + Storage = dyn Any> {
+    id: TypeId,
+    slot: &'a mut Slot<A>
+}
+
+/// A type-erased entry removed from a TypeMap.
+pub struct Erased<A: ?Sized + Storage = dyn Any> {
+    id: TypeId,
+    slot: Slot<A>
+}
+
+impl<'a, A: ?Sized + Storage> ErasedRef<'a, A> {
+    pub(crate) fn new(id: TypeId, slot: &'a Slot<A>) -> ErasedRef<'a, A> {
+        ErasedRef { id, slot }
+    }
+
+    /// The `TypeId` of the entry's key type.
+    pub fn key_id(&self) -> TypeId { self.id }
+
+    /// The name of the entry's key type.
+    pub fn key_name(&self) -> &'static str { self.slot.key }
+
+    /// The name of the entry's value type.
+    pub fn value_name(&self) -> &'static str { self.slot.value }
+
+    /// Get the value, if this is an entry for `K`.
+    pub fn downcast<K: Key>(&self) -> Option<&'a K::Value> {
+        if self.id != TypeId::of::<K>() { return None }
+        self.slot.value()
+    }
+}
+
+impl<'a, A: ?Sized + Storage> Clone for ErasedRef<'a, A> {
+    fn clone(&self) -> Self { *self }
+}
+
+impl<'a, A: ?Sized + Storage> Copy for ErasedRef<'a, A> {}
+
+impl<'a, A: ?Sized + Storage> ErasedMut<'a, A> {
+    /// The `TypeId` of the entry's key type.
+    pub fn key_id(&self) -> TypeId { self.id }
+
+    /// The name of the entry's key type.
+    pub fn key_name(&self) -> &'static str { self.slot.key }
+
+    /// The name of the entry's value type.
+    pub fn value_name(&self) -> &'static str { self.slot.value }
+
+    /// Get the value, if this is an entry for `K`.
+    ///
+    /// Gives back the entry otherwise, so other keys can be tried.
+    pub fn downcast<K: Key>(self) -> Result<&'a mut K::Value, ErasedMut<'a, A>> {
+        if self.id != TypeId::of::<K>() || !self.slot.holds::<K::Value>() { return Err(self) }
+        Ok(self.slot.value_mut().expect("TypeMap entry holds a value of the wrong type"))
+    }
+}
+
+impl<A: ?Sized + Storage> Erased<A> {
+    /// The `TypeId` of the entry's key type.
+    pub fn key_id(&self) -> TypeId { self.id }
+
+    /// The name of the entry's key type.
+    pub fn key_name(&self) -> &'static str { self.slot.key }
+
+    /// The name of the entry's value type.
+    pub fn value_name(&self) -> &'static str { self.slot.value }
+
+    /// Take the value, if this is an entry for `K`.
+    ///
+    /// Gives back the entry otherwise, so other keys can be tried.
+    pub fn downcast<K: Key>(self) -> Result<K::Value, Erased<A>> {
+        if self.id != TypeId::of::<K>() || !self.slot.holds::<K::Value>() { return Err(self) }
+        match self.slot.into_value() {
+            Ok(val) => Ok(val),
+            Err(_) => panic!("TypeMap entry holds a value of the wrong type")
+        }
+    }
+}
+
+macro_rules! impl_debug {
+    ($($ty:ident $(<$lt:lifetime>)*),*) => {$(
+        impl<$($lt,)* A: ?Sized + Storage> fmt::Debug for $ty<$($lt,)* A> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}: {:?}", self.key_name(), self.slot)
+            }
+        }
+    )*}
+}
+
+impl_debug!(ErasedRef<'a>, ErasedMut<'a>, Erased);
+
+/// An iterator over the entries of a TypeMap, created by `TypeMap::iter`.
+pub struct Iter<'a, A: ?Sized + Storage = dyn Any> {
+    inner: store::Iter<'a, A>
+}
+
+/// A mutable iterator over the entries of a TypeMap, created by
+/// `TypeMap::iter_mut`.
+pub struct IterMut<'a, A: ?Sized + Storage = dyn Any> {
+    inner: store::IterMut<'a, A>
+}
+
+/// An iterator over the key types of a TypeMap, created by `TypeMap::keys`.
+///
+/// Yields the `TypeId` and name of each key type.
+pub struct Keys<'a, A: ?Sized + Storage = dyn Any> {
+    inner: store::Iter<'a, A>
+}
+
+/// A draining iterator over the entries of a TypeMap, created by
+/// `TypeMap::drain`.
+pub struct Drain<'a, A: ?Sized + Storage = dyn Any, const N: usize = 0> {
+    inner: store::Drain<'a, A, N>
+}
+
+/// An owning iterator over the entries of a TypeMap.
+pub struct IntoIter<A: ?Sized + Storage = dyn Any, const N: usize = 0> {
+    inner: store::IntoIter<A, N>
+}
+
+impl<'a, A: ?Sized + Storage> Iterator for Iter<'a, A> {
+    type Item = ErasedRef<'a, A>;
+
+    fn next(&mut self) -> Option<ErasedRef<'a, A>> {
+        self.inner.next().map(|(&id, slot)| ErasedRef { id, slot })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+}
+
+impl<'a, A: ?Sized + Storage> Iterator for IterMut<'a, A> {
+    type Item = ErasedMut<'a, A>;
+
+    fn next(&mut self) -> Option<ErasedMut<'a, A>> {
+        self.inner.next().map(|(&id, slot)| ErasedMut { id, slot })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+}
+
+impl<'a, A: ?Sized + Storage> Iterator for Keys<'a, A> {
+    type Item = (TypeId, &'static str);
+
+    fn next(&mut self) -> Option<(TypeId, &'static str)> {
+        self.inner.next().map(|(&id, slot)| (id, slot.key))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+}
+
+impl<'a, A: ?Sized + Storage, const N: usize> Iterator for Drain<'a, A, N> {
+    type Item = Erased<A>;
+
+    fn next(&mut self) -> Option<Erased<A>> {
+        self.inner.next().map(|(id, slot)| Erased { id, slot })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+}
+
+impl<A: ?Sized + Storage, const N: usize> Iterator for IntoIter<A, N> {
+    type Item = Erased<A>;
+
+    fn next(&mut self) -> Option<Erased<A>> {
+        self.inner.next().map(|(id, slot)| Erased { id, slot })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
+}
+
+impl<'a, A: ?Sized + Storage> ExactSizeIterator for Iter<'a, A> {}
+impl<'a, A: ?Sized + Storage> ExactSizeIterator for IterMut<'a, A> {}
+impl<'a, A: ?Sized + Storage> ExactSizeIterator for Keys<'a, A> {}
+impl<'a, A: ?Sized + Storage, const N: usize> ExactSizeIterator for Drain<'a, A, N> {}
+impl<A: ?Sized + Storage, const N: usize> ExactSizeIterator for IntoIter<A, N> {}
+
+impl<A: ?Sized + Storage, S, const N: usize> TypeMap<A, S, N> {
+    /// Iterate over the entries of the map.
+    pub fn iter(&self) -> Iter<'_, A> {
+        Iter { inner: self.data.iter() }
+    }
+
+    /// Iterate mutably over the entries of the map.
+    pub fn iter_mut(&mut self) -> IterMut<'_, A> {
+        IterMut { inner: self.data.iter_mut() }
+    }
+
+    /// Iterate over the key types in the map.
+    pub fn keys(&self) -> Keys<'_, A> {
+        Keys { inner: self.data.iter() }
+    }
+
+    /// Remove all entries from the map, yielding them.
+    ///
+    /// Entries not yielded are dropped when the iterator is.
+    pub fn drain(&mut self) -> Drain<'_, A, N> {
+        Drain { inner: self.data.drain() }
+    }
+}
+
+impl<'a, A: ?Sized + Storage, S, const N: usize> IntoIterator for &'a TypeMap<A, S, N> {
+    type Item = ErasedRef<'a, A>;
+    type IntoIter = Iter<'a, A>;
+
+    fn into_iter(self) -> Iter<'a, A> { self.iter() }
+}
+
+impl<'a, A: ?Sized + Storage, S, const N: usize> IntoIterator for &'a mut TypeMap<A, S, N> {
+    type Item = ErasedMut<'a, A>;
+    type IntoIter = IterMut<'a, A>;
+
+    fn into_iter(self) -> IterMut<'a, A> { self.iter_mut() }
+}
+
+impl<A: ?Sized + Storage, S, const N: usize> IntoIterator for TypeMap<A, S, N> {
+    type Item = Erased<A>;
+    type IntoIter = IntoIter<A, N>;
+
+    fn into_iter(self) -> IntoIter<A, N> {
+        IntoIter { inner: self.data.into_iter() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::any::TypeId;
+
+    use crate::{Key, TypeMap};
+
+    struct Hits;
+
+    impl Key for Hits { type Value = usize; }
+
+    struct Name;
+
+    impl Key for Name { type Value = &'static str; }
+
+    fn map() -> TypeMap {
+        let mut map = TypeMap::new();
+        map.insert::<Hits>(1);
+        map.insert::<Name>("typemap");
+        map
+    }
+
+    #[test] fn test_iter_downcast() {
+        let map = map();
+        let mut hits = 0;
+        for entry in &map {
+            if let Some(val) = entry.downcast::<Hits>() {
+                hits += val;
+            }
+            assert_eq!(entry.downcast::<Name>().is_some(), entry.key_id() == TypeId::of::<Name>());
+        }
+        assert_eq!(hits, 1);
+        assert_eq!(map.iter().len(), 2);
+    }
+
+    #[test] fn test_iter_mut_downcast() {
+        let mut map = map();
+        for entry in map.iter_mut() {
+            if let Ok(val) = entry.downcast::<Hits>() {
+                *val += 1;
+            }
+        }
+        assert_eq!(*map.get::<Hits>().unwrap(), 2);
+    }
+
+    #[test] fn test_keys() {
+        let map = map();
+        let mut keys: Vec<_> = map.keys().collect();
+        keys.sort_by_key(|&(_, name)| name);
+        assert_eq!(keys, vec![(TypeId::of::<Hits>(), "typemap::iter::test::Hits"),
+                              (TypeId::of::<Name>(), "typemap::iter::test::Name")]);
+    }
+
+    #[test] fn test_drain_and_into_iter() {
+        let mut map = map();
+        let mut names = Vec::new();
+        for entry in map.drain() {
+            match entry.downcast::<Hits>() {
+                Ok(hits) => assert_eq!(hits, 1),
+                Err(entry) => names.push(entry.downcast::<Name>().ok().unwrap())
+            }
+        }
+        assert!(map.is_empty());
+        assert_eq!(names, vec!["typemap"]);
+
+        let values: Vec<_> = self::map().into_iter()
+            .filter_map(|entry| entry.downcast::<Hits>().ok())
+            .collect();
+        assert_eq!(values, vec![1]);
+    }
+}
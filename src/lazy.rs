@@ -0,0 +1,251 @@
+//! Values which are built by a factory the first time they are accessed.
+
+use alloc::boxed::Box;
+#[cfg(not(feature = "std"))]
+use core::cell::{Cell, OnceCell};
+use core::any::{type_name, TypeId};
+use core::hash::BuildHasher;
+#[cfg(feature = "std")]
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+use crate::internals::Slot;
+use crate::{Implements, Key, Storage, TypeMap};
+
+#[cfg(feature = "std")]
+type Factory<V> = Box<dyn FnOnce() -> V + Send>;
+
+#[cfg(not(feature = "std"))]
+type Factory<V> = Box<dyn FnOnce() -> V>;
+
+/// A value stored by `TypeMap::insert_lazy`, which is built the first time
+/// it is accessed.
+///
+/// With the `std` feature, a Lazy is `Sync` and concurrent accesses wait
+/// for a single call of the factory. If the factory panics, later accesses
+/// panic as well.
+pub struct Lazy<V> {
+    #[cfg(feature = "std")]
+    value: OnceLock<V>,
+    #[cfg(feature = "std")]
+    factory: Mutex<Option<Factory<V>>>,
+    #[cfg(not(feature = "std"))]
+    value: OnceCell<V>,
+    #[cfg(not(feature = "std"))]
+    factory: Cell<Option<Factory<V>>>
+}
+
+impl<V> Lazy<V> {
+    fn new(factory: Factory<V>) -> Lazy<V> {
+        Lazy { value: Default::default(), factory: Some(factory).into() }
+    }
+
+    /// Get the value, building it if this is the first access.
+    pub(crate) fn force(&self) -> &V {
+        self.value.get_or_init(|| self.take_factory()())
+    }
+
+    fn force_mut(&mut self) -> &mut V {
+        self.force();
+        self.value.get_mut().expect("lazy TypeMap value is not initialized")
+    }
+
+    fn into_value(mut self) -> V {
+        self.force();
+        self.value.take().expect("lazy TypeMap value is not initialized")
+    }
+
+    #[cfg(feature = "std")]
+    fn take_factory(&self) -> Factory<V> {
+        let factory = self.factory.lock().unwrap_or_else(PoisonError::into_inner).take();
+        factory.expect("lazy TypeMap value's factory panicked")
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn take_factory(&self) -> Factory<V> {
+        self.factory.take().expect("lazy TypeMap value's factory panicked")
+    }
+}
+
+impl<V: Clone> Clone for Lazy<V> {
+    /// Clones the value, building it first if needed.
+    fn clone(&self) -> Lazy<V> {
+        Lazy { value: self.force().clone().into(), factory: Default::default() }
+    }
+}
+
+impl<A: ?Sized + Storage> Slot<A> {
+    /// Check if the slot holds a `V`, either directly or lazily.
+    pub(crate) fn holds<V: 'static>(&self) -> bool {
+        self.as_any().is::<V>() || self.as_any().is::<Lazy<V>>()
+    }
+
+    /// Get a reference to the value, if it is a `V`, building it first if
+    /// it is lazy.
+    pub(crate) fn value<V: 'static>(&self) -> Option<&V> {
+        let any = self.as_any();
+        any.downcast_ref().or_else(|| any.downcast_ref::<Lazy<V>>().map(Lazy::force))
+    }
+
+    /// Get a mutable reference to the value, if it is a `V`, building it
+    /// first if it is lazy.
+    pub(crate) fn value_mut<V: 'static>(&mut self) -> Option<&mut V> {
+        if self.as_any().is::<Lazy<V>>() {
+            return self.as_any_mut().downcast_mut::<Lazy<V>>().map(Lazy::force_mut);
+        }
+        self.as_any_mut().downcast_mut()
+    }
+
+    /// Take the value out, if it is a `V`, building it first if it is lazy.
+    pub(crate) fn into_value<V: 'static>(self) -> Result<V, Slot<A>> {
+        self.downcast().or_else(|slot| slot.downcast::<Lazy<V>>().map(Lazy::into_value))
+    }
+
+    /// Take the value out, if it is a `V` which has been built. A lazy value
+    /// which was never accessed is dropped along with its factory.
+    pub(crate) fn into_built<V: 'static>(self) -> Option<V> {
+        match self.downcast() {
+            Ok(val) => Some(val),
+            Err(slot) => slot.downcast::<Lazy<V>>().ok()?.value.into_inner()
+        }
+    }
+}
+
+impl<A: ?Sized + Storage, S: BuildHasher, const N: usize> TypeMap<A, S, N> {
+    /// Insert a factory for the value of a key type, which is called the
+    /// first time the value is accessed.
+    ///
+    /// Lookups through `get`, `get_mut`, `remove` and the like build the
+    /// value transparently, so the key behaves as if `factory()` had been
+    /// inserted. Returns the value previously stored for the key, if any.
+    /// A value which is replaced or removed before it is accessed is never
+    /// built, and `None` is returned in its place.
+    ///
+    /// The factory must be `Send`, as it is kept behind a `Mutex` so that
+    /// the value can be built from any thread sharing the map.
+    #[cfg(feature = "std")]
+    pub fn insert_lazy<K: Key, F>(&mut self, factory: F) -> Option<K::Value>
+    where F: FnOnce() -> K::Value + Send + 'static, Lazy<K::Value>: Implements<A> {
+        self.insert_factory::<K>(Box::new(factory))
+    }
+
+    /// Insert a factory for the value of a key type, which is called the
+    /// first time the value is accessed.
+    ///
+    /// Lookups through `get`, `get_mut`, `remove` and the like build the
+    /// value transparently, so the key behaves as if `factory()` had been
+    /// inserted. Returns the value previously stored for the key, if any.
+    /// A value which is replaced or removed before it is accessed is never
+    /// built, and `None` is returned in its place.
+    #[cfg(not(feature = "std"))]
+    pub fn insert_lazy<K: Key, F>(&mut self, factory: F) -> Option<K::Value>
+    where F: FnOnce() -> K::Value + 'static, Lazy<K::Value>: Implements<A> {
+        self.insert_factory::<K>(Box::new(factory))
+    }
+
+    /// Get a reference to the value for a key type, building it if it was
+    /// inserted by `insert_lazy` and has not been accessed yet.
+    ///
+    /// Only needs `&self`, as a lazy value is built in place.
+    pub fn get_or_init<K: Key>(&self) -> Option<&K::Value> {
+        self.get::<K>()
+    }
+
+    fn insert_factory<K: Key>(&mut self, factory: Factory<K::Value>) -> Option<K::Value>
+    where Lazy<K::Value>: Implements<A> {
+        let mut slot = Slot::new::<K, Lazy<K::Value>>(Lazy::new(factory));
+        // Name the value type, rather than `Lazy`, in errors and `Debug`.
+        slot.value = type_name::<K::Value>();
+        self.data.insert(TypeId::of::<K>(), slot).and_then(Slot::into_built)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::{CloneTypeMap, Entry, Key, TypeMap};
+
+    struct Table;
+
+    impl Key for Table { type Value = Vec<u32>; }
+
+    #[test] fn test_built_on_first_access() {
+        static BUILT: AtomicUsize = AtomicUsize::new(0);
+
+        let mut map = TypeMap::new();
+        map.insert_lazy::<Table, _>(|| {
+            BUILT.fetch_add(1, Ordering::SeqCst);
+            vec![1, 2, 3]
+        });
+        assert!(map.contains::<Table>());
+        assert_eq!(BUILT.load(Ordering::SeqCst), 0);
+
+        assert_eq!(map.get_or_init::<Table>().unwrap(), &[1, 2, 3]);
+        assert_eq!(map.get::<Table>().unwrap(), &[1, 2, 3]);
+        map.get_mut::<Table>().unwrap().push(4);
+        *map.entry::<Table>().or_default() = vec![5];
+        assert_eq!(map.remove::<Table>(), Some(vec![5]));
+        assert_eq!(BUILT.load(Ordering::SeqCst), 1);
+
+        assert_eq!(map.insert_lazy::<Table, _>(|| vec![6]), None);
+        assert_eq!(map.get::<Table>().unwrap(), &[6]);
+        assert_eq!(map.insert_lazy::<Table, _>(|| unreachable!()), Some(vec![6]));
+        assert_eq!(map.insert_lazy::<Table, _>(|| unreachable!()), None);
+        assert_eq!(map.insert::<Table>(vec![7]), None);
+        map.insert_lazy::<Table, _>(|| unreachable!());
+        assert_eq!(map.remove::<Table>(), None);
+        assert!(!map.contains::<Table>());
+        map.insert_lazy::<Table, _>(|| unreachable!());
+        if let Entry::Occupied(mut entry) = map.entry::<Table>() {
+            assert_eq!(entry.insert(vec![8]), None);
+        }
+        assert_eq!(map.get::<Table>().unwrap(), &[8]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test] fn test_shared_between_threads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        use crate::SyncTypeMap;
+
+        let built = Arc::new(AtomicUsize::new(0));
+        let mut map = SyncTypeMap::custom();
+        let counter = built.clone();
+        map.insert_lazy::<Table, _>(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            vec![7]
+        });
+
+        let map = Arc::new(map);
+        let threads: Vec<_> = (0..4).map(|_| {
+            let map = map.clone();
+            thread::spawn(move || map.get_or_init::<Table>().unwrap()[0])
+        }).collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), 7);
+        }
+        assert_eq!(built.load(Ordering::SeqCst), 1);
+    }
+
+    #[test] fn test_small_value_built_through_shared_ref() {
+        struct Flag;
+
+        impl Key for Flag { type Value = u8; }
+
+        let mut map = TypeMap::new();
+        map.insert_lazy::<Flag, _>(|| 7);
+        let map = &map;
+        assert_eq!(map.get_or_init::<Flag>(), Some(&7));
+        assert_eq!(map.get::<Flag>(), Some(&7));
+        assert_eq!(map.iter().next().unwrap().value_name(), "u8");
+        assert_eq!(format!("{:?}", map), "{typemap::lazy::test::test_small_value_built_through_shared_ref::Flag: u8}");
+    }
+
+    #[test] fn test_clone_builds_value() {
+        let mut map = CloneTypeMap::custom();
+        map.insert_lazy::<Table, _>(|| vec![8]);
+        let clone = map.clone();
+        assert_eq!(clone.get::<Table>().unwrap(), &[8]);
+    }
+}
@@ -1,126 +1,673 @@
-#![license = "MIT"]
-#![deny(missing_doc)]
+#![deny(missing_docs)]
 #![deny(warnings)]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
 //! A type-based key value store where one value type is allowed for each key.
+//!
+//! The `std` feature, enabled by default, adds `ConcurrentTypeMap` and the
+//! dependency injection `Container`, and stores spilled entries in a
+//! `HashMap`. Without it the crate only needs `alloc`, and spilled entries
+//! are stored in a `BTreeMap` instead.
+//!
+//! The `serde` feature adds a `Registry` of named key types, through which
+//! a TypeMap can be serialized and deserialized.
 
-use std::any::{Any, AnyRefExt, AnyMutRefExt};
-use std::intrinsics::TypeId;
-use std::collections::HashMap;
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::{type_name, Any, TypeId};
+use core::fmt;
+use core::hash::BuildHasher;
+use core::marker::PhantomData;
+
+pub use bus::{EventBus, Subscription};
+#[cfg(feature = "std")]
+pub use concurrent::{ConcurrentTypeMap, ReadGuard, WriteGuard};
+#[cfg(feature = "std")]
+pub use container::{Container, ContainerError, Lifetime, Resolver, Scope};
+pub use error::TypeMapError;
+pub use hash::{BuildTypeIdHasher, TypeIdHasher};
+pub use internals::{CloneAny, Implements, Storage};
+pub use iter::{Drain, Erased, ErasedMut, ErasedRef, IntoIter, Iter, IterMut, Keys};
+pub use lazy::Lazy;
+pub use many::KeyTuple;
+pub use merge::{Merge, MergePolicy, Mergers};
+pub use observed::{Change, ObservedTypeMap};
+pub use persistent::PersistentTypeMap;
+#[cfg(feature = "serde")]
+pub use registry::{Policy, Registry, Serializable};
+pub use scoped::ScopedTypeMap;
+pub use transaction::Transaction;
+
+use internals::Slot;
+use store::{RawEntry, RawOccupied, RawVacant, Store};
+
+mod bus;
+#[cfg(feature = "std")]
+mod concurrent;
+#[cfg(feature = "std")]
+mod container;
+mod error;
+mod hash;
+mod internals;
+mod iter;
+mod lazy;
+mod many;
+mod merge;
+mod observed;
+mod persistent;
+#[cfg(feature = "serde")]
+mod registry;
+mod scoped;
+mod store;
+mod transaction;
 
 /// A map keyed by types.
 ///
 /// Can contain one value of any type for each key type, as defined
-/// by the Assoc trait.
-pub struct TypeMap {
-    data: HashMap<TypeId, Box<Any>>
+/// by the Key trait. Keys are only ever named as type parameters, so
+/// key types never need to be constructed.
+///
+/// The storage type `A` determines the bounds placed on values; see
+/// `SendTypeMap` and `SyncTypeMap`. Keys are hashed with `S`, which by
+/// default uses the `TypeId` itself as the hash.
+///
+/// Up to `N` entries are kept inline, without allocating a hash table;
+/// see `SmallTypeMap`.
+pub struct TypeMap<A: ?Sized + Storage = dyn Any, S = BuildTypeIdHasher, const N: usize = 0> {
+    data: Store<A, S, N>
 }
 
+/// A TypeMap which keeps up to `N` entries inline, only allocating a hash
+/// table once it holds more.
+///
+/// This saves the allocation when a short lived map is built, at the cost
+/// of slower lookups: inline entries are found by binary search rather
+/// than by hashing.
+pub type SmallTypeMap<const N: usize, A = dyn Any> = TypeMap<A, BuildTypeIdHasher, N>;
+
+/// A TypeMap which can be sent between threads.
+///
+/// All values must be `Send`.
+pub type SendTypeMap = TypeMap<dyn Any + Send>;
+
+/// A TypeMap which can be sent and shared between threads.
+///
+/// All values must be `Send + Sync`.
+pub type SyncTypeMap = TypeMap<dyn Any + Send + Sync>;
+
+/// A TypeMap which can be cloned.
+///
+/// All values must be `Clone`, and cloning the map clones every value.
+pub type CloneTypeMap = TypeMap<dyn CloneAny>;
+
+/// A TypeMap which can be cloned and shared between threads.
+///
+/// All values must be `Clone + Send + Sync`.
+pub type SyncCloneTypeMap = TypeMap<dyn CloneAny + Send + Sync>;
+
 /// This trait defines the relationship between keys and values in a TypeMap.
 ///
-/// It is implemented for Keys, with a phantom type parameter for values.
-pub trait Assoc<Value> {}
+/// It is implemented for Keys, with an associated type for values. A type
+/// can only implement `Key` once, so each key has exactly one value type.
+pub trait Key: Any {
+    /// The type of value stored for this key.
+    type Value: Any;
+}
 
 impl TypeMap {
     /// Create a new, empty TypeMap.
     pub fn new() -> TypeMap {
+        TypeMap::custom()
+    }
+}
+
+impl<A: ?Sized + Storage, const N: usize> TypeMap<A, BuildTypeIdHasher, N> {
+    /// Create a new, empty TypeMap with a custom storage type or inline
+    /// capacity.
+    pub fn custom() -> TypeMap<A, BuildTypeIdHasher, N> {
+        TypeMap::with_hasher(BuildTypeIdHasher::default())
+    }
+}
+
+impl<A: ?Sized + Storage, S: BuildHasher, const N: usize> TypeMap<A, S, N> {
+    /// Create a new, empty TypeMap which hashes keys with `hasher`.
+    pub fn with_hasher(hasher: S) -> TypeMap<A, S, N> {
         TypeMap {
-            data: HashMap::new()
+            data: Store::with_hasher(hasher)
         }
     }
 
     /// Insert a value into the map with a specified key type.
-    pub fn insert<K: Assoc<V> + 'static, V: 'static>(&mut self, _key: K, val: V) -> bool {
-        self.data.insert(TypeId::of::<K>(), box val as Box<Any>)
+    ///
+    /// Returns the value previously stored for the key, if any.
+    pub fn insert<K: Key>(&mut self, val: K::Value) -> Option<K::Value>
+    where K::Value: Implements<A> {
+        self.insert_slot::<K::Value>(TypeId::of::<K>(), Slot::new::<K, K::Value>(val))
+    }
+
+    /// Insert a value into the map with a specified key type, keeping the
+    /// value's `Debug` output for the map's own `Debug` implementation.
+    ///
+    /// Returns the value previously stored for the key, if any.
+    pub fn insert_debug<K: Key>(&mut self, val: K::Value) -> Option<K::Value>
+    where K::Value: Implements<A> + fmt::Debug {
+        self.insert_slot::<K::Value>(TypeId::of::<K>(), Slot::with_debug::<K, K::Value>(val))
+    }
+
+    fn insert_slot<V: 'static>(&mut self, id: TypeId, slot: Slot<A>) -> Option<V> {
+        self.data.insert(id, slot).and_then(Slot::into_built)
+    }
+
+    /// Replace the value stored for a key type, leaving the map unchanged
+    /// if the key has no value.
+    ///
+    /// Returns the old value, or gives back `val` if nothing was replaced.
+    pub fn replace<K: Key>(&mut self, val: K::Value) -> Result<K::Value, K::Value> {
+        match self.get_mut::<K>() {
+            Some(old) => Ok(core::mem::replace(old, val)),
+            None => Err(val)
+        }
     }
 
     /// Find a value in the map and get a reference to it.
-    pub fn find<K: Assoc<V> + 'static, V: 'static>(&self, _key: K) -> Option<&V> {
-        self.data.find(&TypeId::of::<K>()).and_then(|v| v.downcast_ref::<V>())
+    pub fn get<K: Key>(&self) -> Option<&K::Value> {
+        self.try_get::<K>().ok()
     }
 
     /// Find a value in the map and get a mutable reference to it.
-    pub fn find_mut<K: Assoc<V> + 'static, V: 'static>(&mut self, _key: K) -> Option<&mut V> {
-        self.data.find_mut(&TypeId::of::<K>()).and_then(|v| v.downcast_mut::<V>())
+    pub fn get_mut<K: Key>(&mut self) -> Option<&mut K::Value> {
+        self.try_get_mut::<K>().ok()
+    }
+
+    /// Get a reference to the value for a key type, distinguishing a missing
+    /// key from a value of another type.
+    pub fn try_get<K: Key>(&self) -> Result<&K::Value, TypeMapError> {
+        let slot = self.data.get(&TypeId::of::<K>()).ok_or_else(missing::<K>)?;
+        slot.value().ok_or_else(|| mismatch::<K, A>(slot))
+    }
+
+    /// Get a mutable reference to the value for a key type, distinguishing
+    /// a missing key from a value of another type.
+    pub fn try_get_mut<K: Key>(&mut self) -> Result<&mut K::Value, TypeMapError> {
+        let slot = self.data.get_mut(&TypeId::of::<K>()).ok_or_else(missing::<K>)?;
+        if !slot.holds::<K::Value>() {
+            return Err(mismatch::<K, A>(slot));
+        }
+
+        Ok(slot.value_mut().expect("TypeMap entry holds a value of the wrong type"))
     }
 
     /// Check if a key has an associated value stored in the map.
-    pub fn contains<K: Assoc<V> + 'static, V: 'static>(&self, _key: K) -> bool {
+    pub fn contains<K: Key>(&self) -> bool {
         self.data.contains_key(&TypeId::of::<K>())
     }
 
     /// Remove a value from the map.
     ///
-    /// Returns `true` if a value was removed.
-    pub fn remove<K: Assoc<V> + 'static, V: 'static>(&mut self, _key: K) -> bool {
-        self.data.remove(&TypeId::of::<K>())
+    /// Returns the removed value, if there was one.
+    pub fn remove<K: Key>(&mut self) -> Option<K::Value> {
+        self.try_remove::<K>().ok()
     }
-}
 
-impl Collection for TypeMap {
-    fn len(&self) -> uint {
+    /// Remove the value for a key type, distinguishing a missing key from a
+    /// value of another type.
+    ///
+    /// A value of another type is left in the map. A lazy value which was
+    /// never accessed is removed without being built, and reported as
+    /// missing.
+    pub fn try_remove<K: Key>(&mut self) -> Result<K::Value, TypeMapError> {
+        let id = TypeId::of::<K>();
+        let slot = self.data.get(&id).ok_or_else(missing::<K>)?;
+        if !slot.holds::<K::Value>() {
+            return Err(mismatch::<K, A>(slot));
+        }
+
+        self.data.remove(&id).and_then(Slot::into_built).ok_or_else(missing::<K>)
+    }
+
+    /// Take the value out of the map, leaving `K::Value::default()` in its
+    /// place.
+    ///
+    /// Returns `None` and leaves the map unchanged if the key has no value.
+    pub fn take<K: Key>(&mut self) -> Option<K::Value> where K::Value: Default {
+        self.get_mut::<K>().map(core::mem::take)
+    }
+
+    /// Get the entry for a key type for in-place manipulation.
+    pub fn entry<K: Key>(&mut self) -> Entry<'_, K, A, S, N> {
+        match self.data.entry(TypeId::of::<K>()) {
+            RawEntry::Occupied(inner) => Entry::Occupied(OccupiedEntry {
+                inner,
+                phantom: PhantomData
+            }),
+            RawEntry::Vacant(inner) => Entry::Vacant(VacantEntry {
+                inner,
+                phantom: PhantomData
+            })
+        }
+    }
+
+    /// Get the number of values stored in the map.
+    pub fn len(&self) -> usize {
         self.data.len()
     }
-}
 
-impl Mutable for TypeMap {
-    fn clear(&mut self) {
+    /// Return true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Remove all entries from the map.
+    pub fn clear(&mut self) {
         self.data.clear()
     }
 }
 
+fn missing<K>() -> TypeMapError {
+    TypeMapError::Missing { key: type_name::<K>() }
+}
+
+fn mismatch<K: Key, A: ?Sized>(slot: &Slot<A>) -> TypeMapError {
+    TypeMapError::TypeMismatch {
+        key: type_name::<K>(),
+        expected: type_name::<K::Value>(),
+        found: slot.value
+    }
+}
+
+impl<A: ?Sized + Storage, S: BuildHasher + Default, const N: usize> Default for TypeMap<A, S, N> {
+    fn default() -> TypeMap<A, S, N> {
+        TypeMap::with_hasher(S::default())
+    }
+}
+
+impl<A: ?Sized + Storage, S, const N: usize> fmt::Debug for TypeMap<A, S, N> {
+    /// Lists the key and value type names of every entry, sorted by key,
+    /// along with the value itself for entries inserted by `insert_debug`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut slots: Vec<&Slot<A>> = self.data.iter().map(|(_, slot)| slot).collect();
+        slots.sort_by_key(|slot| slot.key);
+        f.debug_map().entries(slots.into_iter().map(|slot| (TypeName(slot.key), slot))).finish()
+    }
+}
+
+struct TypeName(&'static str);
+
+impl fmt::Debug for TypeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl<A: ?Sized + Storage, S: Clone, const N: usize> Clone for TypeMap<A, S, N> where Box<A>: Clone {
+    fn clone(&self) -> TypeMap<A, S, N> {
+        TypeMap {
+            data: self.data.clone()
+        }
+    }
+}
+
+/// A view into a single entry of a TypeMap, which may be vacant or occupied.
+pub enum Entry<'a, K, A: ?Sized + Storage = dyn Any, S = BuildTypeIdHasher, const N: usize = 0> {
+    /// An occupied entry.
+    Occupied(OccupiedEntry<'a, K, A, N>),
+    /// A vacant entry.
+    Vacant(VacantEntry<'a, K, A, S, N>)
+}
+
+/// An occupied entry in a TypeMap.
+pub struct OccupiedEntry<'a, K, A: ?Sized + Storage = dyn Any, const N: usize = 0> {
+    inner: RawOccupied<'a, A, N>,
+    phantom: PhantomData<fn() -> K>
+}
+
+/// A vacant entry in a TypeMap.
+pub struct VacantEntry<'a, K, A: ?Sized + Storage = dyn Any, S = BuildTypeIdHasher, const N: usize = 0> {
+    inner: RawVacant<'a, A, S, N>,
+    phantom: PhantomData<fn() -> K>
+}
+
+impl<'a, K, A, S, const N: usize> Entry<'a, K, A, S, N>
+where K: Key, K::Value: Implements<A>, A: ?Sized + Storage, S: BuildHasher {
+    /// Ensure a value is in the entry by inserting `default` if empty,
+    /// and get a mutable reference to the value.
+    pub fn or_insert(self, default: K::Value) -> &'a mut K::Value {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default)
+        }
+    }
+
+    /// Ensure a value is in the entry by inserting the result of `default`
+    /// if empty, and get a mutable reference to the value.
+    pub fn or_insert_with<F: FnOnce() -> K::Value>(self, default: F) -> &'a mut K::Value {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default())
+        }
+    }
+
+    /// Ensure a value is in the entry by inserting `K::Value::default()` if
+    /// empty, and get a mutable reference to the value.
+    pub fn or_default(self) -> &'a mut K::Value where K::Value: Default {
+        self.or_insert_with(K::Value::default)
+    }
+
+    /// Modify the value in an occupied entry before any potential insert.
+    pub fn and_modify<F: FnOnce(&mut K::Value)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
+            },
+            Entry::Vacant(entry) => Entry::Vacant(entry)
+        }
+    }
+}
+
+impl<'a, K, A, const N: usize> OccupiedEntry<'a, K, A, N>
+where K: Key, A: ?Sized + Storage {
+    /// Get a reference to the value in the entry.
+    pub fn get(&self) -> &K::Value {
+        self.inner.get().value().expect("TypeMap entry holds a value of the wrong type")
+    }
+
+    /// Get a mutable reference to the value in the entry.
+    pub fn get_mut(&mut self) -> &mut K::Value {
+        self.inner.get_mut().value_mut().expect("TypeMap entry holds a value of the wrong type")
+    }
+
+    /// Convert the entry into a mutable reference to its value,
+    /// bound to the lifetime of the map.
+    pub fn into_mut(self) -> &'a mut K::Value {
+        self.inner.into_mut().value_mut().expect("TypeMap entry holds a value of the wrong type")
+    }
+
+    /// Set the value of the entry, returning the old value.
+    ///
+    /// Returns `None` if the old value was inserted by `insert_lazy` and
+    /// never accessed, in which case it is not built.
+    pub fn insert(&mut self, val: K::Value) -> Option<K::Value> where K::Value: Implements<A> {
+        let slot = self.inner.get_mut();
+        match slot.as_any_mut().downcast_mut() {
+            Some(old) => Some(core::mem::replace(old, val)),
+            None => core::mem::replace(slot, Slot::new::<K, K::Value>(val)).into_built()
+        }
+    }
+
+    /// Take the value out of the entry, removing it from the map.
+    pub fn remove(self) -> K::Value {
+        match self.inner.remove().into_value() {
+            Ok(val) => val,
+            Err(_) => panic!("TypeMap entry holds a value of the wrong type")
+        }
+    }
+}
+
+impl<'a, K, A, S, const N: usize> VacantEntry<'a, K, A, S, N>
+where K: Key, K::Value: Implements<A>, A: ?Sized + Storage, S: BuildHasher {
+    /// Set the value of the entry, returning a mutable reference to it.
+    pub fn insert(self, val: K::Value) -> &'a mut K::Value {
+        self.inner.insert(Slot::new::<K, K::Value>(val)).as_any_mut().downcast_mut().expect("TypeMap entry holds a value of the wrong type")
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{TypeMap, Assoc};
+    use std::any::{Any, TypeId};
+    use std::cell::Cell;
+    use std::collections::hash_map::RandomState;
+    use std::marker::PhantomData;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, RwLock};
+    use std::thread;
 
-    #[deriving(Show, PartialEq)]
-    struct Key;
+    use super::{TypeMap, SmallTypeMap, SendTypeMap, SyncTypeMap, CloneTypeMap, SyncCloneTypeMap, CloneAny, Key, Entry, TypeMapError};
+    use super::internals::Slot;
 
-    #[deriving(Show, PartialEq)]
+    #[derive(Debug, PartialEq)]
+    struct KeyType;
+
+    #[derive(Debug, PartialEq)]
     struct Value;
 
-    impl Assoc<Value> for Key {}
+    impl Key for KeyType { type Value = Value; }
+
+    #[derive(Debug, PartialEq)]
+    struct Other;
+
+    struct Counter;
+
+    impl Key for Counter { type Value = usize; }
 
     #[test] fn test_pairing() {
         let mut map = TypeMap::new();
-        map.insert::<Key, Value>(Key, Value);
-        assert_eq!(*map.find::<Key, Value>(Key).unwrap(), Value);
-        assert!(map.contains::<Key, Value>(Key));
+        map.insert::<KeyType>(Value);
+        assert_eq!(*map.get::<KeyType>().unwrap(), Value);
+        assert!(map.contains::<KeyType>());
     }
 
-    #[test] fn test_pairing_with_hashmap_syntax() {
+    #[test] fn test_remove() {
         let mut map = TypeMap::new();
-        map.insert(Key, Value);
-        assert_eq!(*map.find::<Key, Value>(Key).unwrap(), Value);
-        assert!(map.contains::<Key, Value>(Key));
+        map.insert::<KeyType>(Value);
+        assert!(map.contains::<KeyType>());
+        map.remove::<KeyType>();
+        assert!(!map.contains::<KeyType>());
     }
 
-    #[test] fn test_finding_with_hashmap_syntax() {
+    #[test] fn test_uninhabited_and_phantom_keys() {
+        enum Private {}
+
+        impl Key for Private { type Value = &'static str; }
+
+        struct Marker<T>(PhantomData<T>);
+
+        impl<T: 'static> Key for Marker<T> { type Value = usize; }
+
         let mut map = TypeMap::new();
-        map.insert(Key, Value);
-        assert_eq!(*map.find(Key).unwrap(), Value);
+        map.insert::<Private>("private");
+        map.insert::<Marker<u8>>(8);
+        map.insert::<Marker<u16>>(16);
+        assert_eq!(*map.get::<Private>().unwrap(), "private");
+        assert_eq!(*map.get::<Marker<u8>>().unwrap(), 8);
+        assert_eq!(map.remove::<Marker<u16>>(), Some(16));
+        assert!(!map.contains::<Marker<u16>>());
+    }
+
+    #[test] fn test_custom_hasher() {
+        let mut map: TypeMap<dyn Any, RandomState> = TypeMap::default();
+        map.insert::<Counter>(1);
+        assert_eq!(*map.get::<Counter>().unwrap(), 1);
     }
 
-    #[test] fn test_contains_with_hashmap_syntax() {
+    #[test] fn test_len_and_clear() {
         let mut map = TypeMap::new();
-        map.insert(Key, Value);
-        assert!(map.contains(Key));
+        assert!(map.is_empty());
+        map.insert::<KeyType>(Value);
+        assert_eq!(map.len(), 1);
+        map.clear();
+        assert!(map.is_empty());
     }
 
-    #[test] fn test_remove() {
+    #[test] fn test_entry_or_insert() {
         let mut map = TypeMap::new();
-        map.insert::<Key, Value>(Key, Value);
-        assert!(map.contains::<Key, Value>(Key));
-        map.remove::<Key, Value>(Key);
-        assert!(!map.contains::<Key, Value>(Key));
+        *map.entry::<Counter>().or_insert(1) += 1;
+        *map.entry::<Counter>().or_insert(10) += 1;
+        assert_eq!(*map.get::<Counter>().unwrap(), 3);
     }
 
-    #[test] fn test_remove_with_hashmap_syntax() {
+    #[test] fn test_entry_or_default_and_modify() {
         let mut map = TypeMap::new();
-        map.insert(Key, Value);
-        assert!(map.contains(Key));
-        map.remove(Key);
-        assert!(!map.contains(Key));
+        map.entry::<Counter>().and_modify(|c| *c += 5).or_default();
+        assert_eq!(*map.get::<Counter>().unwrap(), 0);
+        map.entry::<Counter>().and_modify(|c| *c += 5).or_insert_with(|| 100);
+        assert_eq!(*map.get::<Counter>().unwrap(), 5);
+    }
+
+    #[test] fn test_entry_occupied_insert_and_remove() {
+        let mut map = TypeMap::new();
+        map.insert::<Counter>(7);
+        match map.entry::<Counter>() {
+            Entry::Occupied(mut entry) => {
+                assert_eq!(entry.insert(8), Some(7));
+                assert_eq!(entry.remove(), 8);
+            },
+            Entry::Vacant(_) => panic!("expected an occupied entry")
+        }
+        assert!(!map.contains::<Counter>());
+    }
+
+    #[test] fn test_insert_returns_previous_value() {
+        let mut map = TypeMap::new();
+        assert_eq!(map.insert::<Counter>(1), None);
+        assert_eq!(map.insert::<Counter>(2), Some(1));
+        assert_eq!(*map.get::<Counter>().unwrap(), 2);
+    }
+
+    #[test] fn test_remove_returns_value() {
+        let mut map = TypeMap::new();
+        map.insert::<Counter>(3);
+        assert_eq!(map.remove::<Counter>(), Some(3));
+        assert_eq!(map.remove::<Counter>(), None);
+    }
+
+    #[test] fn test_replace() {
+        let mut map = TypeMap::new();
+        assert_eq!(map.replace::<Counter>(1), Err(1));
+        assert!(map.is_empty());
+        map.insert::<Counter>(2);
+        assert_eq!(map.replace::<Counter>(3), Ok(2));
+        assert_eq!(*map.get::<Counter>().unwrap(), 3);
+    }
+
+    #[test] fn test_take() {
+        let mut map = TypeMap::new();
+        assert_eq!(map.take::<Counter>(), None);
+        map.insert::<Counter>(4);
+        assert_eq!(map.take::<Counter>(), Some(4));
+        assert_eq!(*map.get::<Counter>().unwrap(), 0);
+    }
+
+    #[test] fn test_send_map_crosses_threads() {
+        let mut map = SendTypeMap::custom();
+        map.insert::<Counter>(5);
+        let map = thread::spawn(move || {
+            *map.get_mut::<Counter>().unwrap() += 1;
+            map
+        }).join().unwrap();
+        assert_eq!(*map.get::<Counter>().unwrap(), 6);
+    }
+
+    #[test] fn test_sync_map_behind_rwlock() {
+        let map = Arc::new(RwLock::new(SyncTypeMap::custom()));
+        map.write().unwrap().insert::<Counter>(1);
+        let reader = map.clone();
+        let seen = thread::spawn(move || {
+            *reader.read().unwrap().get::<Counter>().unwrap()
+        }).join().unwrap();
+        assert_eq!(seen, 1);
+        assert_eq!(*map.write().unwrap().entry::<Counter>().or_insert(0), 1);
+    }
+
+    #[test] fn test_interior_mutability_through_get() {
+        struct Hits;
+
+        impl Key for Hits { type Value = AtomicUsize; }
+
+        struct Flag;
+
+        impl Key for Flag { type Value = Cell<bool>; }
+
+        let mut map = SyncTypeMap::custom();
+        map.insert::<Hits>(AtomicUsize::new(0));
+        map.get::<Hits>().unwrap().fetch_add(1, Ordering::SeqCst);
+        assert_eq!(map.get::<Hits>().unwrap().load(Ordering::SeqCst), 1);
+
+        let mut map = SmallTypeMap::<1>::custom();
+        map.insert::<Flag>(Cell::new(false));
+        map.get::<Flag>().unwrap().set(true);
+        assert!(map.get::<Flag>().unwrap().get());
+    }
+
+    #[test] fn test_clone_map_deep_clones_values() {
+        let mut map = CloneTypeMap::custom();
+        map.insert::<Counter>(1);
+        let mut forked = map.clone();
+        *forked.get_mut::<Counter>().unwrap() += 1;
+        assert_eq!(*map.get::<Counter>().unwrap(), 1);
+        assert_eq!(*forked.get::<Counter>().unwrap(), 2);
     }
-}
 
+    #[test] fn test_sync_clone_map_crosses_threads() {
+        let mut template = SyncCloneTypeMap::custom();
+        template.insert::<Counter>(10);
+        let forked = template.clone();
+        let seen = thread::spawn(move || *forked.get::<Counter>().unwrap()).join().unwrap();
+        assert_eq!(seen, 10);
+    }
+
+    #[test] fn test_debug_lists_type_names() {
+        let mut map = TypeMap::new();
+        map.insert::<KeyType>(Value);
+        map.insert_debug::<Counter>(3);
+        assert_eq!(format!("{:?}", map),
+                   "{typemap::test::Counter: usize = 3, typemap::test::KeyType: typemap::test::Value}");
+    }
+
+    #[test] fn test_try_get_errors() {
+        let mut map = TypeMap::new();
+        assert_eq!(map.try_get::<KeyType>(),
+                   Err(TypeMapError::Missing { key: "typemap::test::KeyType" }));
+
+        // Only reachable by bypassing the typed API.
+        map.data.insert(TypeId::of::<KeyType>(), Slot::new::<KeyType, Other>(Other));
+        let mismatch = TypeMapError::TypeMismatch {
+            key: "typemap::test::KeyType",
+            expected: "typemap::test::Value",
+            found: "typemap::test::Other"
+        };
+        assert_eq!(map.try_get::<KeyType>(), Err(mismatch));
+        assert_eq!(map.try_get_mut::<KeyType>(), Err(mismatch));
+        assert_eq!(map.try_remove::<KeyType>(), Err(mismatch));
+        assert_eq!(mismatch.to_string(),
+                   "TypeMap key `typemap::test::KeyType` holds a `typemap::test::Other`, not a `typemap::test::Value`");
+    }
+
+    #[test] fn test_try_get_mut_and_try_remove() {
+        let mut map = TypeMap::new();
+        map.insert::<Counter>(1);
+        *map.try_get_mut::<Counter>().unwrap() += 1;
+        assert_eq!(map.try_remove::<Counter>(), Ok(2));
+        assert!(matches!(map.try_remove::<Counter>(), Err(TypeMapError::Missing { .. })));
+    }
+
+    #[test] fn test_small_map_spills() {
+        struct Small<const I: usize>;
+
+        impl<const I: usize> Key for Small<I> { type Value = usize; }
+
+        let mut map = SmallTypeMap::<2, dyn CloneAny>::custom();
+        map.insert::<Small<0>>(0);
+        *map.entry::<Small<1>>().or_insert(0) += 1;
+        assert_eq!(map.insert::<Small<1>>(1), Some(1));
+        assert_eq!(map.len(), 2);
+
+        map.insert::<Small<2>>(2);
+        *map.entry::<Small<3>>().or_default() += 3;
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.iter().count(), 4);
+        assert_eq!(*map.get::<Small<0>>().unwrap(), 0);
+        assert_eq!(*map.get::<Small<3>>().unwrap(), 3);
+
+        assert_eq!(map.remove::<Small<1>>(), Some(1));
+        let forked = map.clone();
+        map.clear();
+        assert!(map.is_empty());
+        map.insert::<Small<2>>(4);
+        assert_eq!(*map.get::<Small<2>>().unwrap(), 4);
+        assert_eq!(forked.len(), 3);
+        assert!(!forked.contains::<Small<1>>());
+    }
+}
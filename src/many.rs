@@ -0,0 +1,110 @@
+//! Mutable borrows of the values of several keys at once.
+
+use core::any::{type_name, TypeId};
+use core::hash::BuildHasher;
+
+use crate::{Key, Storage, TypeMap, TypeMapError};
+
+/// A tuple of key types, whose values can be borrowed mutably at once
+/// through `TypeMap::get_many_mut`.
+///
+/// Implemented for tuples of up to eight `Key`s.
+pub trait KeyTuple {
+    /// A tuple of a mutable reference to the value of each key type, if it
+    /// has one.
+    type Mut<'a>;
+
+    #[doc(hidden)]
+    fn get_many_mut<A, S, const N: usize>(map: &mut TypeMap<A, S, N>) -> Result<Self::Mut<'_>, TypeMapError>
+    where A: ?Sized + Storage, S: BuildHasher;
+}
+
+macro_rules! impl_key_tuple {
+    ($($K:ident $slot:ident),*) => {
+        impl<$($K: Key),*> KeyTuple for ($($K,)*) {
+            type Mut<'a> = ($(Option<&'a mut $K::Value>,)*);
+
+            fn get_many_mut<A, S, const N: usize>(map: &mut TypeMap<A, S, N>) -> Result<Self::Mut<'_>, TypeMapError>
+            where A: ?Sized + Storage, S: BuildHasher {
+                let ids = [$(TypeId::of::<$K>()),*];
+                let names = [$(type_name::<$K>()),*];
+                for (index, id) in ids.iter().enumerate() {
+                    if ids[..index].contains(id) {
+                        return Err(TypeMapError::DuplicateKey { key: names[index] });
+                    }
+                }
+
+                let [$($slot),*] = map.data.get_disjoint_mut(ids.each_ref());
+                Ok(($($slot.and_then(|slot| slot.value_mut::<$K::Value>()),)*))
+            }
+        }
+    }
+}
+
+impl_key_tuple!(K1 k1);
+impl_key_tuple!(K1 k1, K2 k2);
+impl_key_tuple!(K1 k1, K2 k2, K3 k3);
+impl_key_tuple!(K1 k1, K2 k2, K3 k3, K4 k4);
+impl_key_tuple!(K1 k1, K2 k2, K3 k3, K4 k4, K5 k5);
+impl_key_tuple!(K1 k1, K2 k2, K3 k3, K4 k4, K5 k5, K6 k6);
+impl_key_tuple!(K1 k1, K2 k2, K3 k3, K4 k4, K5 k5, K6 k6, K7 k7);
+impl_key_tuple!(K1 k1, K2 k2, K3 k3, K4 k4, K5 k5, K6 k6, K7 k7, K8 k8);
+
+impl<A: ?Sized + Storage, S: BuildHasher, const N: usize> TypeMap<A, S, N> {
+    /// Get mutable references to the values of several key types at once.
+    ///
+    /// `Ks` is a tuple of key types, such as `(DbPool, Metrics)`, and each
+    /// reference is `None` if its key has no value. Returns an error if
+    /// the same key type appears more than once, as its value could then
+    /// be borrowed mutably twice.
+    pub fn get_many_mut<Ks: KeyTuple>(&mut self) -> Result<Ks::Mut<'_>, TypeMapError> {
+        Ks::get_many_mut(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Key, SmallTypeMap, TypeMap, TypeMapError};
+
+    struct DbPool;
+
+    impl Key for DbPool { type Value = Vec<&'static str>; }
+
+    struct Metrics;
+
+    impl Key for Metrics { type Value = usize; }
+
+    struct Missing;
+
+    impl Key for Missing { type Value = (); }
+
+    #[test] fn test_get_many_mut() {
+        let mut map = TypeMap::new();
+        map.insert::<DbPool>(vec!["primary"]);
+        map.insert::<Metrics>(0);
+
+        let (pool, metrics, missing) = map.get_many_mut::<(DbPool, Metrics, Missing)>().unwrap();
+        pool.unwrap().push("replica");
+        *metrics.unwrap() += 1;
+        assert!(missing.is_none());
+
+        assert_eq!(map.get::<DbPool>().unwrap(), &["primary", "replica"]);
+        assert_eq!(map.get::<Metrics>(), Some(&1));
+    }
+
+    #[test] fn test_get_many_mut_inline() {
+        let mut map = SmallTypeMap::<4>::custom();
+        map.insert::<Metrics>(1);
+        let (metrics, pool) = map.get_many_mut::<(Metrics, DbPool)>().unwrap();
+        *metrics.unwrap() += 1;
+        assert!(pool.is_none());
+        assert_eq!(map.get::<Metrics>(), Some(&2));
+    }
+
+    #[test] fn test_get_many_mut_duplicates() {
+        let mut map = TypeMap::new();
+        map.insert::<Metrics>(0);
+        assert_eq!(map.get_many_mut::<(Metrics, DbPool, Metrics)>().err(),
+                   Some(TypeMapError::DuplicateKey { key: "typemap::many::test::Metrics" }));
+    }
+}
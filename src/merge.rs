@@ -0,0 +1,219 @@
+//! Moving the entries of one TypeMap into another.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::any::{Any, TypeId};
+use core::fmt;
+use core::hash::BuildHasher;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::hash::Hash;
+
+use crate::internals::Slot;
+use crate::store::RawEntry;
+use crate::{Key, Storage, TypeMap};
+
+type MergeFn<A> = fn(&mut Slot<A>, Slot<A>);
+
+/// A value which can absorb another value of the same type.
+///
+/// Used by `MergePolicy::Merge` to combine the values of key types that
+/// are in both maps.
+pub trait Merge {
+    /// Merge `other` into `self`, with `other` taking precedence where
+    /// the two disagree.
+    fn merge(&mut self, other: Self);
+}
+
+/// What to do with a key type that is in both maps being merged.
+pub enum MergePolicy<'a, A: ?Sized + Storage = dyn Any> {
+    /// Keep the value already in the map, dropping the other.
+    KeepExisting,
+    /// Replace the value already in the map with the other.
+    Overwrite,
+    /// Merge the other value into the one already in the map, for key types
+    /// registered with the Mergers. Other key types are overwritten.
+    Merge(&'a Mergers<A>)
+}
+
+/// A registry of key types whose values are combined through their `Merge`
+/// implementation by `MergePolicy::Merge`.
+pub struct Mergers<A: ?Sized + Storage = dyn Any> {
+    merges: BTreeMap<TypeId, MergeFn<A>>
+}
+
+impl<A: ?Sized + Storage> Mergers<A> {
+    /// Create a new, empty Mergers.
+    pub fn new() -> Mergers<A> {
+        Mergers { merges: BTreeMap::new() }
+    }
+
+    /// Merge the values of a key type, rather than overwriting them.
+    pub fn register<K: Key>(&mut self) -> &mut Mergers<A> where K::Value: Merge {
+        self.merges.insert(TypeId::of::<K>(), merge_slot::<K::Value, A>);
+        self
+    }
+
+    /// Check if the values of a key type are merged.
+    pub fn contains<K: Key>(&self) -> bool {
+        self.merges.contains_key(&TypeId::of::<K>())
+    }
+}
+
+fn merge_slot<V: Merge + 'static, A: ?Sized + Storage>(existing: &mut Slot<A>, other: Slot<A>) {
+    let Ok(other) = other.into_value::<V>() else { panic!("TypeMap entry holds a value of the wrong type") };
+    existing.value_mut::<V>().expect("TypeMap entry holds a value of the wrong type").merge(other);
+}
+
+impl<A: ?Sized + Storage, S: BuildHasher, const N: usize> TypeMap<A, S, N> {
+    /// Move all entries from another map into this one, replacing the
+    /// values of key types in both.
+    ///
+    /// This is the same as `merge` with `MergePolicy::Overwrite`.
+    pub fn extend<H, const M: usize>(&mut self, other: TypeMap<A, H, M>) {
+        self.merge(other, MergePolicy::Overwrite)
+    }
+
+    /// Move all entries from another map into this one, combining the
+    /// values of key types in both according to `policy`.
+    pub fn merge<H, const M: usize>(&mut self, other: TypeMap<A, H, M>, policy: MergePolicy<'_, A>) {
+        for (id, slot) in other.data.into_iter() {
+            let mut existing = match self.data.entry(id) {
+                RawEntry::Occupied(existing) => existing,
+                RawEntry::Vacant(vacant) => { vacant.insert(slot); continue }
+            };
+            match policy {
+                MergePolicy::KeepExisting => {},
+                MergePolicy::Overwrite => *existing.get_mut() = slot,
+                MergePolicy::Merge(mergers) => match mergers.merges.get(&id) {
+                    Some(merge) => merge(existing.get_mut(), slot),
+                    None => *existing.get_mut() = slot
+                }
+            }
+        }
+    }
+}
+
+impl<T> Merge for Vec<T> {
+    /// Appends the other vector's elements.
+    fn merge(&mut self, mut other: Vec<T>) {
+        self.append(&mut other);
+    }
+}
+
+impl Merge for String {
+    /// Appends the other string.
+    fn merge(&mut self, other: String) {
+        self.push_str(&other);
+    }
+}
+
+impl<K: Ord, V> Merge for BTreeMap<K, V> {
+    /// Inserts the other map's entries, replacing those of keys in both.
+    fn merge(&mut self, other: BTreeMap<K, V>) {
+        self.extend(other);
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K: Eq + Hash, V, S: BuildHasher> Merge for HashMap<K, V, S> {
+    /// Inserts the other map's entries, replacing those of keys in both.
+    fn merge(&mut self, other: HashMap<K, V, S>) {
+        self.extend(other);
+    }
+}
+
+impl<'a, A: ?Sized + Storage> Clone for MergePolicy<'a, A> {
+    fn clone(&self) -> MergePolicy<'a, A> {
+        *self
+    }
+}
+
+impl<'a, A: ?Sized + Storage> Copy for MergePolicy<'a, A> {}
+
+impl<'a, A: ?Sized + Storage> fmt::Debug for MergePolicy<'a, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            MergePolicy::KeepExisting => f.write_str("KeepExisting"),
+            MergePolicy::Overwrite => f.write_str("Overwrite"),
+            MergePolicy::Merge(mergers) => f.debug_tuple("Merge").field(mergers).finish()
+        }
+    }
+}
+
+impl<A: ?Sized + Storage> Default for Mergers<A> {
+    fn default() -> Mergers<A> {
+        Mergers::new()
+    }
+}
+
+impl<A: ?Sized + Storage> fmt::Debug for Mergers<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mergers").field("keys", &self.merges.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Key, SmallTypeMap, TypeMap};
+
+    use super::{MergePolicy, Mergers};
+
+    struct Plugins;
+
+    impl Key for Plugins { type Value = Vec<&'static str>; }
+
+    struct Theme;
+
+    impl Key for Theme { type Value = &'static str; }
+
+    struct Verbose;
+
+    impl Key for Verbose { type Value = bool; }
+
+    fn defaults() -> TypeMap {
+        let mut map = TypeMap::new();
+        map.insert::<Plugins>(vec!["core"]);
+        map.insert::<Theme>("light");
+        map
+    }
+
+    fn overrides() -> SmallTypeMap<2> {
+        let mut map = SmallTypeMap::custom();
+        map.insert::<Plugins>(vec!["git"]);
+        map.insert::<Theme>("dark");
+        map.insert::<Verbose>(true);
+        map
+    }
+
+    #[test] fn test_extend() {
+        let mut map = defaults();
+        map.extend(overrides());
+        assert_eq!(map.get::<Plugins>().unwrap(), &["git"]);
+        assert_eq!(map.get::<Theme>(), Some(&"dark"));
+        assert_eq!(map.get::<Verbose>(), Some(&true));
+    }
+
+    #[test] fn test_keep_existing() {
+        let mut map = defaults();
+        map.merge(overrides(), MergePolicy::KeepExisting);
+        assert_eq!(map.get::<Plugins>().unwrap(), &["core"]);
+        assert_eq!(map.get::<Theme>(), Some(&"light"));
+        assert_eq!(map.get::<Verbose>(), Some(&true));
+    }
+
+    #[test] fn test_merge() {
+        let mut mergers = Mergers::new();
+        mergers.register::<Plugins>();
+        assert!(!mergers.contains::<Theme>());
+
+        let mut map = defaults();
+        map.insert_lazy::<Plugins, _>(|| vec!["core", "lazy"]);
+        map.merge(overrides(), MergePolicy::Merge(&mergers));
+        assert_eq!(map.get::<Plugins>().unwrap(), &["core", "lazy", "git"]);
+        assert_eq!(map.get::<Theme>(), Some(&"dark"));
+        assert_eq!(map.len(), 3);
+    }
+}
@@ -0,0 +1,317 @@
+//! A TypeMap which notifies observers when its entries change.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::any::{Any, TypeId};
+use core::fmt;
+use core::ops::Deref;
+
+use crate::internals::Slot;
+use crate::{ErasedRef, Implements, Key, Storage, TypeMap};
+
+type InsertFn<A> = fn(&dyn Any, Option<&Slot<A>>, &Slot<A>);
+type RemoveFn<A> = fn(&dyn Any, &Slot<A>);
+type ChangeFn<A> = fn(&dyn Any, Change<'_, A>);
+
+/// An observer, stored as a value of the map's storage type so that the
+/// map keeps the auto traits of its storage, along with a function which
+/// calls it.
+struct Observer<A: ?Sized, C> {
+    f: Box<A>,
+    call: C
+}
+
+/// A map keyed by types, which calls back registered observers when a
+/// value is inserted or removed.
+///
+/// Observers for a key type are called first, in the order they were
+/// registered, followed by the observers of every change. Only changes
+/// made through `insert`, `remove` and `clear` are observed; the map can
+/// otherwise be read through `Deref`.
+///
+/// Observers are stored like values, so they must meet the bounds of the
+/// storage type: `Send` for `dyn Any + Send`, `Clone` for `dyn CloneAny`,
+/// and so on.
+pub struct ObservedTypeMap<A: ?Sized + Storage = dyn Any> {
+    map: TypeMap<A>,
+    on_insert: BTreeMap<TypeId, Vec<Observer<A, InsertFn<A>>>>,
+    on_remove: BTreeMap<TypeId, Vec<Observer<A, RemoveFn<A>>>>,
+    on_change: Vec<Observer<A, ChangeFn<A>>>
+}
+
+/// A change to an ObservedTypeMap, passed to the observers registered by
+/// `ObservedTypeMap::observe`.
+pub enum Change<'a, A: ?Sized + Storage = dyn Any> {
+    /// A value was inserted, replacing `old` if there was one.
+    Insert {
+        /// The replaced entry.
+        old: Option<ErasedRef<'a, A>>,
+        /// The inserted entry.
+        new: ErasedRef<'a, A>
+    },
+    /// A value was removed.
+    Remove(ErasedRef<'a, A>)
+}
+
+impl ObservedTypeMap {
+    /// Create a new, empty ObservedTypeMap.
+    pub fn new() -> ObservedTypeMap {
+        ObservedTypeMap::custom()
+    }
+}
+
+impl<A: ?Sized + Storage> ObservedTypeMap<A> {
+    /// Create a new, empty ObservedTypeMap with a custom storage type.
+    pub fn custom() -> ObservedTypeMap<A> {
+        ObservedTypeMap::from(TypeMap::custom())
+    }
+
+    /// Call `f` with the old and new values whenever a value is inserted
+    /// for a key type.
+    pub fn on_insert<K: Key, F>(&mut self, f: F)
+    where F: Fn(Option<&K::Value>, &K::Value) + Implements<A> + 'static {
+        self.on_insert.entry(TypeId::of::<K>()).or_default().push(Observer {
+            f: f.into_object(),
+            call: call_on_insert::<K::Value, F, A>
+        });
+    }
+
+    /// Call `f` with the removed value whenever a value is removed for a
+    /// key type.
+    pub fn on_remove<K: Key, F>(&mut self, f: F)
+    where F: Fn(&K::Value) + Implements<A> + 'static {
+        self.on_remove.entry(TypeId::of::<K>()).or_default().push(Observer {
+            f: f.into_object(),
+            call: call_on_remove::<K::Value, F, A>
+        });
+    }
+
+    /// Call `f` with every change to the map, whatever its key type.
+    pub fn observe<F>(&mut self, f: F)
+    where F: Fn(Change<'_, A>) + Implements<A> + 'static {
+        self.on_change.push(Observer { f: f.into_object(), call: call_observe::<F, A> });
+    }
+
+    /// Insert a value into the map with a specified key type, notifying
+    /// observers.
+    ///
+    /// Returns the value previously stored for the key, if any. A replaced
+    /// lazy value is built if an observer of its key type is passed it.
+    pub fn insert<K: Key>(&mut self, val: K::Value) -> Option<K::Value>
+    where K::Value: Implements<A> {
+        let id = TypeId::of::<K>();
+        let old = self.map.data.insert(id, Slot::new::<K, K::Value>(val));
+        let new = self.map.data.get(&id).expect("TypeMap entry missing after insert");
+
+        for observer in self.on_insert.get(&id).into_iter().flatten() {
+            (observer.call)(observer.f.as_any(), old.as_ref(), new);
+        }
+        for observer in &self.on_change {
+            (observer.call)(observer.f.as_any(), Change::Insert {
+                old: old.as_ref().map(|old| ErasedRef::new(id, old)),
+                new: ErasedRef::new(id, new)
+            });
+        }
+        old.and_then(Slot::into_built)
+    }
+
+    /// Remove a value from the map, notifying observers.
+    ///
+    /// Returns the removed value, if there was one. A lazy value is built if
+    /// an observer of its key type is passed it.
+    pub fn remove<K: Key>(&mut self) -> Option<K::Value> {
+        let id = TypeId::of::<K>();
+        if !self.map.data.get(&id).is_some_and(|slot| slot.holds::<K::Value>()) { return None }
+
+        let old = self.map.data.remove(&id)?;
+        self.removed(id, &old);
+        old.into_built()
+    }
+
+    /// Remove all values from the map, notifying observers of each.
+    pub fn clear(&mut self) {
+        let removed: Vec<_> = self.map.data.drain().collect();
+        for (id, old) in &removed {
+            self.removed(*id, old);
+        }
+    }
+
+    /// Unwrap the map, dropping its observers.
+    pub fn into_inner(self) -> TypeMap<A> {
+        self.map
+    }
+
+    fn removed(&self, id: TypeId, old: &Slot<A>) {
+        for observer in self.on_remove.get(&id).into_iter().flatten() {
+            (observer.call)(observer.f.as_any(), old);
+        }
+        for observer in &self.on_change {
+            (observer.call)(observer.f.as_any(), Change::Remove(ErasedRef::new(id, old)));
+        }
+    }
+}
+
+fn call_on_insert<V, F, A>(f: &dyn Any, old: Option<&Slot<A>>, new: &Slot<A>)
+where V: 'static, F: Fn(Option<&V>, &V) + 'static, A: ?Sized + Storage {
+    if let (Some(f), Some(new)) = (f.downcast_ref::<F>(), new.value()) {
+        f(old.and_then(Slot::value), new)
+    }
+}
+
+fn call_on_remove<V, F, A>(f: &dyn Any, old: &Slot<A>)
+where V: 'static, F: Fn(&V) + 'static, A: ?Sized + Storage {
+    if let (Some(f), Some(old)) = (f.downcast_ref::<F>(), old.value()) {
+        f(old)
+    }
+}
+
+fn call_observe<F, A>(f: &dyn Any, change: Change<'_, A>)
+where F: Fn(Change<'_, A>) + 'static, A: ?Sized + Storage {
+    if let Some(f) = f.downcast_ref::<F>() {
+        f(change)
+    }
+}
+
+impl<A: ?Sized + Storage> From<TypeMap<A>> for ObservedTypeMap<A> {
+    /// Observe changes to `map` from now on.
+    fn from(map: TypeMap<A>) -> ObservedTypeMap<A> {
+        ObservedTypeMap {
+            map,
+            on_insert: BTreeMap::new(),
+            on_remove: BTreeMap::new(),
+            on_change: Vec::new()
+        }
+    }
+}
+
+impl<A: ?Sized + Storage> Default for ObservedTypeMap<A> {
+    fn default() -> ObservedTypeMap<A> {
+        ObservedTypeMap::custom()
+    }
+}
+
+impl<A: ?Sized + Storage> Deref for ObservedTypeMap<A> {
+    type Target = TypeMap<A>;
+
+    fn deref(&self) -> &TypeMap<A> {
+        &self.map
+    }
+}
+
+impl<A: ?Sized + Storage> fmt::Debug for ObservedTypeMap<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.map.fmt(f)
+    }
+}
+
+impl<'a, A: ?Sized + Storage> fmt::Debug for Change<'a, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Change::Insert { ref old, ref new } => {
+                f.debug_struct("Insert").field("old", old).field("new", new).finish()
+            },
+            Change::Remove(ref old) => f.debug_tuple("Remove").field(old).finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+
+    use crate::{Key, TypeMap};
+
+    use super::{Change, ObservedTypeMap};
+
+    struct LogLevel;
+
+    impl Key for LogLevel { type Value = &'static str; }
+
+    struct Port;
+
+    impl Key for Port { type Value = u16; }
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    fn record(log: &Log, event: String) {
+        log.lock().unwrap().push(event);
+    }
+
+    #[test] fn test_typed_observers() {
+        let log = Log::default();
+        let mut map = ObservedTypeMap::new();
+
+        let events = log.clone();
+        map.on_insert::<LogLevel, _>(move |old, new| record(&events, format!("{:?} -> {}", old, new)));
+        let events = log.clone();
+        map.on_remove::<LogLevel, _>(move |old| record(&events, format!("removed {}", old)));
+
+        assert_eq!(map.insert::<LogLevel>("info"), None);
+        assert_eq!(map.insert::<LogLevel>("debug"), Some("info"));
+        map.insert::<Port>(80);
+        assert_eq!(map.remove::<LogLevel>(), Some("debug"));
+        assert_eq!(map.remove::<LogLevel>(), None);
+
+        assert_eq!(*log.lock().unwrap(), ["None -> info", "Some(\"info\") -> debug", "removed debug"]);
+        assert_eq!(map.get::<Port>(), Some(&80));
+    }
+
+    #[test] fn test_change_observers() {
+        let log = Log::default();
+        let mut map = ObservedTypeMap::from(TypeMap::new());
+        map.insert::<Port>(80);
+
+        let events = log.clone();
+        map.observe(move |change| record(&events, match change {
+            Change::Insert { old, new } => format!("insert {:?} {}", old.map(|old| old.value_name()), new.key_name()),
+            Change::Remove(old) => format!("remove {:?}", old.downcast::<Port>())
+        }));
+
+        map.insert::<Port>(8080);
+        map.insert::<LogLevel>("warn");
+        map.clear();
+        assert!(map.is_empty());
+
+        let mut events = log.lock().unwrap().clone();
+        events[2..].sort();
+        assert_eq!(events, [
+            "insert Some(\"u16\") typemap::observed::test::Port",
+            "insert None typemap::observed::test::LogLevel",
+            "remove None",
+            "remove Some(8080)"
+        ]);
+    }
+
+    #[test] fn test_lazy_entries() {
+        let log = Log::default();
+        let mut map = TypeMap::new();
+        map.insert_lazy::<Port, _>(|| 80);
+        map.insert_lazy::<LogLevel, _>(|| "info");
+        let mut map = ObservedTypeMap::from(map);
+
+        let events = log.clone();
+        map.on_insert::<Port, _>(move |old, new| record(&events, format!("{:?} -> {}", old, new)));
+        let events = log.clone();
+        map.on_remove::<LogLevel, _>(move |old| record(&events, format!("removed {}", old)));
+
+        assert_eq!(map.insert::<Port>(443), Some(80));
+        assert_eq!(map.remove::<LogLevel>(), Some("info"));
+        assert_eq!(*log.lock().unwrap(), ["Some(80) -> 443", "removed info"]);
+    }
+
+    #[test] fn test_local_observers() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut map = ObservedTypeMap::new();
+
+        let events = log.clone();
+        map.on_insert::<Port, _>(move |_, &new| events.borrow_mut().push(new));
+        let events = log.clone();
+        map.observe(move |_| events.borrow_mut().push(0));
+
+        map.insert::<Port>(80);
+        assert_eq!(*log.borrow(), [80, 0]);
+    }
+}
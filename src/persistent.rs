@@ -0,0 +1,388 @@
+//! An immutable TypeMap which shares unchanged entries between versions.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::any::{Any, TypeId};
+use core::fmt;
+use core::hash::BuildHasher;
+
+use crate::internals::Slot;
+use crate::{BuildTypeIdHasher, Implements, Key, Storage, TypeMapError};
+
+/// The number of hash bits consumed by each level of the trie.
+const BITS: u32 = 5;
+
+/// A persistent map keyed by types.
+///
+/// `insert` and `remove` leave the map unchanged and return a new one,
+/// which shares every entry and trie node not on the path to the changed
+/// key. Cloning a PersistentTypeMap is constant time, and never clones a
+/// value.
+///
+/// Entries are stored in a hash array mapped trie keyed by the hash of
+/// each key's `TypeId`.
+pub struct PersistentTypeMap<A: ?Sized + Storage = dyn Any> {
+    root: Arc<Node<A>>,
+    len: usize
+}
+
+enum Node<A: ?Sized> {
+    /// A trie level, holding a child for each set bit of `bitmap`.
+    Branch { bitmap: u32, children: Vec<Child<A>> },
+    /// Entries whose hashes are entirely equal.
+    Collision(Vec<Leaf<A>>)
+}
+
+enum Child<A: ?Sized> {
+    Leaf(Leaf<A>),
+    Node(Arc<Node<A>>)
+}
+
+struct Leaf<A: ?Sized> {
+    hash: u64,
+    id: TypeId,
+    slot: Arc<Slot<A>>
+}
+
+impl PersistentTypeMap {
+    /// Create a new, empty PersistentTypeMap.
+    pub fn new() -> PersistentTypeMap {
+        PersistentTypeMap::custom()
+    }
+}
+
+impl<A: ?Sized + Storage> PersistentTypeMap<A> {
+    /// Create a new, empty PersistentTypeMap with a custom storage type.
+    pub fn custom() -> PersistentTypeMap<A> {
+        PersistentTypeMap {
+            root: Arc::new(Node::Branch { bitmap: 0, children: Vec::new() }),
+            len: 0
+        }
+    }
+
+    /// Return a map with `val` stored for a key type, in place of any
+    /// previous value.
+    #[must_use]
+    pub fn insert<K: Key>(&self, val: K::Value) -> PersistentTypeMap<A>
+    where K::Value: Implements<A> {
+        let id = TypeId::of::<K>();
+        let leaf = Leaf { hash: hash(id), id, slot: Arc::new(Slot::new::<K, K::Value>(val)) };
+        let (root, replaced) = self.root.insert(leaf, 0);
+        PersistentTypeMap {
+            root: Arc::new(root),
+            len: if replaced { self.len } else { self.len + 1 }
+        }
+    }
+
+    /// Return a map without a value for a key type.
+    ///
+    /// Returns a clone of this map if the key has no value.
+    #[must_use]
+    pub fn remove<K: Key>(&self) -> PersistentTypeMap<A> {
+        let id = TypeId::of::<K>();
+        match self.root.remove(hash(id), id, 0) {
+            Some(root) => PersistentTypeMap { root: Arc::new(root), len: self.len - 1 },
+            None => self.clone()
+        }
+    }
+
+    /// Find a value in the map and get a reference to it.
+    pub fn get<K: Key>(&self) -> Option<&K::Value> {
+        self.try_get::<K>().ok()
+    }
+
+    /// Get a reference to the value for a key type, distinguishing a missing
+    /// key from a value of another type.
+    pub fn try_get<K: Key>(&self) -> Result<&K::Value, TypeMapError> {
+        let id = TypeId::of::<K>();
+        let slot = self.root.get(hash(id), id, 0).ok_or_else(crate::missing::<K>)?;
+        slot.as_any().downcast_ref().ok_or_else(|| crate::mismatch::<K, A>(slot))
+    }
+
+    /// Check if a key has an associated value stored in the map.
+    pub fn contains<K: Key>(&self) -> bool {
+        let id = TypeId::of::<K>();
+        self.root.get(hash(id), id, 0).is_some()
+    }
+
+    /// Get the number of values stored in the map.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Return true if the map contains no values.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+fn hash(id: TypeId) -> u64 {
+    BuildTypeIdHasher::default().hash_one(id)
+}
+
+/// The bit of a branch's bitmap which `hash` selects at `shift`.
+fn bit(hash: u64, shift: u32) -> u32 {
+    1 << ((hash >> shift) & ((1 << BITS) - 1))
+}
+
+/// The position in a branch's children of the child for `bit`.
+fn index(bitmap: u32, bit: u32) -> usize {
+    (bitmap & (bit - 1)).count_ones() as usize
+}
+
+impl<A: ?Sized> Node<A> {
+    fn get(&self, hash: u64, id: TypeId, shift: u32) -> Option<&Slot<A>> {
+        match *self {
+            Node::Branch { bitmap, ref children } => {
+                let bit = bit(hash, shift);
+                if bitmap & bit == 0 { return None }
+                match children[index(bitmap, bit)] {
+                    Child::Leaf(ref leaf) => (leaf.id == id).then(|| &*leaf.slot),
+                    Child::Node(ref node) => node.get(hash, id, shift + BITS)
+                }
+            },
+            Node::Collision(ref leaves) => {
+                leaves.iter().find(|leaf| leaf.id == id).map(|leaf| &*leaf.slot)
+            }
+        }
+    }
+
+    /// Copy this node with `leaf` inserted, and whether it replaced an
+    /// existing leaf.
+    fn insert(&self, leaf: Leaf<A>, shift: u32) -> (Node<A>, bool) {
+        match *self {
+            Node::Branch { bitmap, ref children } => {
+                let bit = bit(leaf.hash, shift);
+                let index = index(bitmap, bit);
+                let mut children = children.clone();
+                if bitmap & bit == 0 {
+                    children.insert(index, Child::Leaf(leaf));
+                    return (Node::Branch { bitmap: bitmap | bit, children }, false);
+                }
+
+                let (child, replaced) = match children[index] {
+                    Child::Leaf(ref old) if old.id == leaf.id => (Child::Leaf(leaf), true),
+                    Child::Leaf(ref old) => {
+                        (Child::Node(Arc::new(Node::pair(old.clone(), leaf, shift + BITS))), false)
+                    },
+                    Child::Node(ref node) => {
+                        let (node, replaced) = node.insert(leaf, shift + BITS);
+                        (Child::Node(Arc::new(node)), replaced)
+                    }
+                };
+                children[index] = child;
+                (Node::Branch { bitmap, children }, replaced)
+            },
+            Node::Collision(ref leaves) => {
+                let mut leaves = leaves.clone();
+                match leaves.iter().position(|old| old.id == leaf.id) {
+                    Some(index) => {
+                        leaves[index] = leaf;
+                        (Node::Collision(leaves), true)
+                    },
+                    None => {
+                        leaves.push(leaf);
+                        (Node::Collision(leaves), false)
+                    }
+                }
+            }
+        }
+    }
+
+    /// A node holding two leaves with different keys.
+    fn pair(a: Leaf<A>, b: Leaf<A>, shift: u32) -> Node<A> {
+        if shift >= u64::BITS { return Node::Collision(vec![a, b]) }
+
+        let (bit_a, bit_b) = (bit(a.hash, shift), bit(b.hash, shift));
+        let children = if bit_a == bit_b {
+            vec![Child::Node(Arc::new(Node::pair(a, b, shift + BITS)))]
+        } else if bit_a < bit_b {
+            vec![Child::Leaf(a), Child::Leaf(b)]
+        } else {
+            vec![Child::Leaf(b), Child::Leaf(a)]
+        };
+        Node::Branch { bitmap: bit_a | bit_b, children }
+    }
+
+    /// Copy this node without the leaf for `id`, or return `None` if it
+    /// has no such leaf.
+    fn remove(&self, hash: u64, id: TypeId, shift: u32) -> Option<Node<A>> {
+        match *self {
+            Node::Branch { bitmap, ref children } => {
+                let bit = bit(hash, shift);
+                if bitmap & bit == 0 { return None }
+
+                let index = index(bitmap, bit);
+                let child = match children[index] {
+                    Child::Leaf(ref leaf) if leaf.id == id => None,
+                    Child::Leaf(_) => return None,
+                    Child::Node(ref node) => node.remove(hash, id, shift + BITS)?.into_child()
+                };
+
+                let mut children = children.clone();
+                match child {
+                    Some(child) => {
+                        children[index] = child;
+                        Some(Node::Branch { bitmap, children })
+                    },
+                    None => {
+                        children.remove(index);
+                        Some(Node::Branch { bitmap: bitmap & !bit, children })
+                    }
+                }
+            },
+            Node::Collision(ref leaves) => {
+                let index = leaves.iter().position(|leaf| leaf.id == id)?;
+                let mut leaves = leaves.clone();
+                leaves.remove(index);
+                Some(Node::Collision(leaves))
+            }
+        }
+    }
+
+    /// Convert a node left by a removal into the child which should take
+    /// its place, pulling a lone leaf up into its parent.
+    fn into_child(self) -> Option<Child<A>> {
+        match self {
+            Node::Branch { mut children, .. } if children.len() == 1 && matches!(children[0], Child::Leaf(_)) => {
+                children.pop()
+            },
+            Node::Collision(mut leaves) if leaves.len() == 1 => leaves.pop().map(Child::Leaf),
+            Node::Branch { ref children, .. } if children.is_empty() => None,
+            node => Some(Child::Node(Arc::new(node)))
+        }
+    }
+
+    fn slots<'a>(&'a self, slots: &mut Vec<&'a Slot<A>>) {
+        match *self {
+            Node::Branch { ref children, .. } => for child in children {
+                match *child {
+                    Child::Leaf(ref leaf) => slots.push(&leaf.slot),
+                    Child::Node(ref node) => node.slots(slots)
+                }
+            },
+            Node::Collision(ref leaves) => slots.extend(leaves.iter().map(|leaf| &*leaf.slot))
+        }
+    }
+}
+
+impl<A: ?Sized> Clone for Child<A> {
+    fn clone(&self) -> Child<A> {
+        match *self {
+            Child::Leaf(ref leaf) => Child::Leaf(leaf.clone()),
+            Child::Node(ref node) => Child::Node(node.clone())
+        }
+    }
+}
+
+impl<A: ?Sized> Clone for Leaf<A> {
+    fn clone(&self) -> Leaf<A> {
+        Leaf { hash: self.hash, id: self.id, slot: self.slot.clone() }
+    }
+}
+
+impl<A: ?Sized + Storage> Clone for PersistentTypeMap<A> {
+    fn clone(&self) -> PersistentTypeMap<A> {
+        PersistentTypeMap { root: self.root.clone(), len: self.len }
+    }
+}
+
+impl<A: ?Sized + Storage> Default for PersistentTypeMap<A> {
+    fn default() -> PersistentTypeMap<A> {
+        PersistentTypeMap::custom()
+    }
+}
+
+impl<A: ?Sized + Storage> fmt::Debug for PersistentTypeMap<A> {
+    /// Lists entries in the same form as `TypeMap`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut slots = Vec::with_capacity(self.len);
+        self.root.slots(&mut slots);
+        slots.sort_by_key(|slot| slot.key);
+        f.debug_map().entries(slots.into_iter().map(|slot| (crate::TypeName(slot.key), slot))).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::any::{Any, TypeId};
+    use std::sync::Arc;
+
+    use crate::internals::Slot;
+    use crate::Key;
+
+    use super::{Leaf, Node, PersistentTypeMap};
+
+    struct Small<const I: usize>;
+
+    impl<const I: usize> Key for Small<I> { type Value = usize; }
+
+    macro_rules! insert_all {
+        ($map:expr; $($i:literal)*) => {{
+            let mut map = $map;
+            $(map = map.insert::<Small<$i>>($i);)*
+            map
+        }}
+    }
+
+    #[test] fn test_insert_and_remove_share_entries() {
+        let empty = PersistentTypeMap::new();
+        let one = empty.insert::<Small<0>>(0);
+        let two = one.insert::<Small<1>>(1);
+        let replaced = two.insert::<Small<0>>(10);
+        let removed = replaced.remove::<Small<1>>();
+
+        assert!(empty.is_empty());
+        assert_eq!(one.get::<Small<0>>(), Some(&0));
+        assert!(!one.contains::<Small<1>>());
+        assert_eq!((two.len(), replaced.len(), removed.len()), (2, 2, 1));
+        assert_eq!(two.get::<Small<0>>(), Some(&0));
+        assert_eq!(replaced.get::<Small<0>>(), Some(&10));
+        assert_eq!(removed.get::<Small<0>>(), Some(&10));
+        assert_eq!(removed.get::<Small<1>>(), None);
+        assert_eq!(removed.remove::<Small<1>>().len(), 1);
+        assert!(std::ptr::eq(one.get::<Small<0>>().unwrap(), two.get::<Small<0>>().unwrap()));
+    }
+
+    #[test] fn test_many_keys() {
+        let map = insert_all!(PersistentTypeMap::new();
+            0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19
+            20 21 22 23 24 25 26 27 28 29 30 31 32 33 34 35 36 37 38 39);
+        assert_eq!(map.len(), 40);
+        assert_eq!(map.get::<Small<0>>(), Some(&0));
+        assert_eq!(map.get::<Small<39>>(), Some(&39));
+
+        let map = insert_all!(map; 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19);
+        assert_eq!(map.len(), 40);
+
+        let mut removed = map.clone();
+        removed = removed.remove::<Small<3>>().remove::<Small<17>>().remove::<Small<38>>();
+        assert_eq!(removed.len(), 37);
+        assert!(!removed.contains::<Small<17>>());
+        assert_eq!(removed.get::<Small<18>>(), Some(&18));
+        assert_eq!(map.get::<Small<17>>(), Some(&17));
+    }
+
+    #[test] fn test_hash_collisions() {
+        fn leaf<K: Key<Value = usize>>(val: usize) -> Leaf<dyn Any + Send + Sync> {
+            Leaf { hash: 7, id: TypeId::of::<K>(), slot: Arc::new(Slot::new::<K, usize>(val)) }
+        }
+
+        let root = Node::Branch { bitmap: 0, children: Vec::new() };
+        let (root, _) = root.insert(leaf::<Small<0>>(0), 0);
+        let (root, replaced) = root.insert(leaf::<Small<1>>(1), 0);
+        assert!(!replaced);
+        let (root, replaced) = root.insert(leaf::<Small<1>>(2), 0);
+        assert!(replaced);
+
+        let get = |root: &Node<dyn Any + Send + Sync>, id| root.get(7, id, 0).map(|slot| *slot.as_any().downcast_ref::<usize>().unwrap());
+        assert_eq!(get(&root, TypeId::of::<Small<0>>()), Some(0));
+        assert_eq!(get(&root, TypeId::of::<Small<1>>()), Some(2));
+
+        let root = root.remove(7, TypeId::of::<Small<0>>(), 0).unwrap();
+        assert_eq!(get(&root, TypeId::of::<Small<0>>()), None);
+        assert_eq!(get(&root, TypeId::of::<Small<1>>()), Some(2));
+        assert!(root.remove(7, TypeId::of::<Small<0>>(), 0).is_none());
+    }
+}
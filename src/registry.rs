@@ -0,0 +1,291 @@
+//! Serialization of TypeMaps through a registry of named key types.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::any::{type_name, Any, TypeId};
+use core::fmt;
+use core::hash::BuildHasher;
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, Visitor};
+use serde::ser::{self, Serialize, SerializeMap, Serializer};
+
+use crate::internals::Slot;
+use crate::{BuildTypeIdHasher, Implements, Key, Storage, TypeMap};
+
+/// What to do with an entry the registry cannot handle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Leave the entry out.
+    Skip,
+    /// Fail with an error naming the entry.
+    Error
+}
+
+/// A registry of key types which can be serialized, by stable name.
+///
+/// A TypeMap is serialized as a map from the registered name of each key
+/// type to its value. Entries whose key type is not registered are handled
+/// according to `on_unregistered`, and names which are not registered are
+/// handled during deserialization according to `on_unknown`. Both default
+/// to `Policy::Error`.
+pub struct Registry<A: ?Sized + Storage = dyn Any> {
+    by_id: BTreeMap<TypeId, Registration<A>>,
+    by_name: BTreeMap<&'static str, TypeId>,
+    unregistered: Policy,
+    unknown: Policy
+}
+
+struct Registration<A: ?Sized> {
+    name: &'static str,
+    serialize: fn(&Slot<A>) -> Option<&dyn erased_serde::Serialize>,
+    deserialize: fn(&mut dyn erased_serde::Deserializer<'_>) -> Result<Slot<A>, erased_serde::Error>
+}
+
+impl<A: ?Sized + Storage> Registry<A> {
+    /// Create a new, empty Registry.
+    pub fn new() -> Registry<A> {
+        Registry {
+            by_id: BTreeMap::new(),
+            by_name: BTreeMap::new(),
+            unregistered: Policy::Error,
+            unknown: Policy::Error
+        }
+    }
+
+    /// Register a key type under a stable name.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name or the key type is already registered.
+    pub fn register<K: Key>(&mut self, name: &'static str) -> &mut Registry<A>
+    where K::Value: Serialize + DeserializeOwned + Implements<A> {
+        let id = TypeId::of::<K>();
+        assert!(!self.by_name.contains_key(name), "TypeMap registry name `{}` is already registered", name);
+        assert!(!self.by_id.contains_key(&id), "TypeMap registry key `{}` is already registered", type_name::<K>());
+
+        self.by_name.insert(name, id);
+        self.by_id.insert(id, Registration {
+            name,
+            serialize: serialize_value::<K::Value, A>,
+            deserialize: deserialize_slot::<K, A>
+        });
+        self
+    }
+
+    /// Set what to do with entries whose key type is not registered when
+    /// serializing.
+    pub fn on_unregistered(&mut self, policy: Policy) -> &mut Registry<A> {
+        self.unregistered = policy;
+        self
+    }
+
+    /// Set what to do with names which are not registered when deserializing.
+    pub fn on_unknown(&mut self, policy: Policy) -> &mut Registry<A> {
+        self.unknown = policy;
+        self
+    }
+
+    /// Get a serializable view of a TypeMap.
+    pub fn serializable<'a, H, const N: usize>(&'a self, map: &'a TypeMap<A, H, N>) -> Serializable<'a, A, H, N> {
+        Serializable { registry: self, map }
+    }
+
+    /// Deserialize a TypeMap containing registered key types.
+    pub fn deserialize_map<'de, D: Deserializer<'de>>(&self, deserializer: D) -> Result<TypeMap<A>, D::Error> {
+        DeserializeSeed::deserialize(self, deserializer)
+    }
+
+    /// Deserialize registered key types into an existing TypeMap, such as
+    /// one with a custom hasher or inline capacity.
+    ///
+    /// Deserialized values replace any already in the map for the same key
+    /// type. On error, the map may hold some of the deserialized values.
+    pub fn deserialize_into<'de, D, H, const N: usize>(&self, deserializer: D, map: &mut TypeMap<A, H, N>)
+                                                       -> Result<(), D::Error>
+    where D: Deserializer<'de>, H: BuildHasher {
+        deserializer.deserialize_map(MapVisitor { registry: self, map })
+    }
+}
+
+impl<A: ?Sized + Storage> Default for Registry<A> {
+    fn default() -> Registry<A> {
+        Registry::new()
+    }
+}
+
+fn serialize_value<V: Serialize + 'static, A: ?Sized + Storage>(slot: &Slot<A>) -> Option<&dyn erased_serde::Serialize> {
+    slot.value::<V>().map(|val| val as &dyn erased_serde::Serialize)
+}
+
+fn deserialize_slot<K, A>(deserializer: &mut dyn erased_serde::Deserializer<'_>) -> Result<Slot<A>, erased_serde::Error>
+where K: Key, K::Value: DeserializeOwned + Implements<A>, A: ?Sized + Storage {
+    erased_serde::deserialize::<K::Value>(deserializer).map(Slot::new::<K, K::Value>)
+}
+
+/// A serializable view of a TypeMap, created by `Registry::serializable`.
+pub struct Serializable<'a, A: ?Sized + Storage = dyn Any, H = BuildTypeIdHasher, const N: usize = 0> {
+    registry: &'a Registry<A>,
+    map: &'a TypeMap<A, H, N>
+}
+
+impl<'a, A: ?Sized + Storage, H, const N: usize> Serialize for Serializable<'a, A, H, N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut entries = Vec::with_capacity(self.map.data.len());
+        for (id, slot) in self.map.data.iter() {
+            match self.registry.by_id.get(id) {
+                Some(registration) => match (registration.serialize)(slot) {
+                    Some(val) => entries.push((registration.name, val)),
+                    None => return Err(ser::Error::custom(format_args!(
+                        "TypeMap key `{}` holds an unregistered value type `{}`", slot.key, slot.value)))
+                },
+                None if self.registry.unregistered == Policy::Skip => {},
+                None => return Err(ser::Error::custom(format_args!(
+                    "TypeMap key `{}` is not registered", slot.key)))
+            }
+        }
+        entries.sort_by_key(|&(name, _)| name);
+
+        let mut map = serializer.serialize_map(Some(entries.len()))?;
+        for (name, val) in entries {
+            map.serialize_entry(name, val)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de, A: ?Sized + Storage> DeserializeSeed<'de> for &Registry<A> {
+    type Value = TypeMap<A>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<TypeMap<A>, D::Error> {
+        let mut map = TypeMap::custom();
+        self.deserialize_into(deserializer, &mut map)?;
+        Ok(map)
+    }
+}
+
+struct MapVisitor<'a, A: ?Sized + Storage, H, const N: usize> {
+    registry: &'a Registry<A>,
+    map: &'a mut TypeMap<A, H, N>
+}
+
+impl<'de, 'a, A: ?Sized + Storage, H: BuildHasher, const N: usize> Visitor<'de> for MapVisitor<'a, A, H, N> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a map of registered TypeMap keys to values")
+    }
+
+    fn visit_map<M: MapAccess<'de>>(self, mut access: M) -> Result<(), M::Error> {
+        let mut seen = BTreeSet::new();
+        while let Some(name) = access.next_key::<String>()? {
+            let id = match self.registry.by_name.get(&*name) {
+                Some(&id) => id,
+                None if self.registry.unknown == Policy::Skip => {
+                    access.next_value::<IgnoredAny>()?;
+                    continue;
+                },
+                None => return Err(de::Error::custom(format_args!(
+                    "unknown TypeMap key `{}`", name)))
+            };
+
+            let registration = &self.registry.by_id[&id];
+            if !seen.insert(id) {
+                return Err(de::Error::duplicate_field(registration.name));
+            }
+
+            let slot = access.next_value_seed(SlotSeed { registration })?;
+            self.map.data.insert(id, slot);
+        }
+        Ok(())
+    }
+}
+
+struct SlotSeed<'a, A: ?Sized> {
+    registration: &'a Registration<A>
+}
+
+impl<'de, 'a, A: ?Sized + Storage> DeserializeSeed<'de> for SlotSeed<'a, A> {
+    type Value = Slot<A>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Slot<A>, D::Error> {
+        let mut erased = <dyn erased_serde::Deserializer<'_>>::erase(deserializer);
+        (self.registration.deserialize)(&mut erased).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Policy, Registry};
+    use crate::{Key, SmallTypeMap, TypeMap};
+
+    struct User;
+
+    impl Key for User { type Value = String; }
+
+    struct Visits;
+
+    impl Key for Visits { type Value = u32; }
+
+    struct Scratch;
+
+    impl Key for Scratch { type Value = Vec<u8>; }
+
+    fn registry() -> Registry {
+        let mut registry = Registry::new();
+        registry.register::<User>("user").register::<Visits>("visits");
+        registry
+    }
+
+    #[test] fn test_round_trip() {
+        let mut map = TypeMap::new();
+        map.insert::<User>("reem".to_string());
+        map.insert::<Visits>(3);
+
+        let registry = registry();
+        let json = serde_json::to_string(&registry.serializable(&map)).unwrap();
+        assert_eq!(json, r#"{"user":"reem","visits":3}"#);
+
+        let map = registry.deserialize_map(&mut serde_json::Deserializer::from_str(&json)).unwrap();
+        assert_eq!(map.get::<User>().unwrap(), "reem");
+        assert_eq!(*map.get::<Visits>().unwrap(), 3);
+    }
+
+    #[test] fn test_unregistered_entries() {
+        let mut map = TypeMap::new();
+        map.insert::<Visits>(1);
+        map.insert::<Scratch>(vec![1, 2, 3]);
+
+        let mut registry = registry();
+        assert!(serde_json::to_string(&registry.serializable(&map)).is_err());
+
+        registry.on_unregistered(Policy::Skip);
+        let json = serde_json::to_string(&registry.serializable(&map)).unwrap();
+        assert_eq!(json, r#"{"visits":1}"#);
+    }
+
+    #[test] fn test_unknown_entries() {
+        let json = r#"{"visits":1,"scratch":[1,2,3]}"#;
+
+        let mut registry = registry();
+        assert!(registry.deserialize_map(&mut serde_json::Deserializer::from_str(json)).is_err());
+
+        registry.on_unknown(Policy::Skip);
+        let map = registry.deserialize_map(&mut serde_json::Deserializer::from_str(json)).unwrap();
+        assert_eq!(map.len(), 1);
+        assert_eq!(*map.get::<Visits>().unwrap(), 1);
+    }
+
+    #[test] fn test_deserialize_into() {
+        let registry = registry();
+        let mut map = SmallTypeMap::<2>::custom();
+        map.insert::<Visits>(1);
+        registry.deserialize_into(&mut serde_json::Deserializer::from_str(r#"{"visits":2,"user":"reem"}"#), &mut map).unwrap();
+        assert_eq!(map.get::<User>().unwrap(), "reem");
+        assert_eq!(*map.get::<Visits>().unwrap(), 2);
+
+        let json = r#"{"visits":1,"visits":2}"#;
+        let err = registry.deserialize_map(&mut serde_json::Deserializer::from_str(json)).unwrap_err();
+        assert!(err.to_string().starts_with("duplicate field `visits`"), "{}", err);
+    }
+}
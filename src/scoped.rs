@@ -0,0 +1,238 @@
+//! A TypeMap layered over a shared parent, for nested contexts.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::any::Any;
+use core::fmt;
+
+use crate::{Implements, Key, Storage, TypeMap, TypeMapError};
+
+/// A map keyed by types, which falls back to a parent map for keys it
+/// does not hold itself.
+///
+/// Lookups search the local layer first, then each parent in turn, while
+/// insertions and removals only ever affect the local layer. Parents are
+/// shared through an `Arc`, so one global or per-connection map can sit
+/// beneath any number of shorter lived layers.
+pub struct ScopedTypeMap<A: ?Sized + Storage = dyn Any> {
+    local: TypeMap<A>,
+    parent: Option<Arc<ScopedTypeMap<A>>>
+}
+
+impl ScopedTypeMap {
+    /// Create a new, empty ScopedTypeMap with no parent.
+    pub fn new() -> ScopedTypeMap {
+        ScopedTypeMap::custom()
+    }
+}
+
+impl<A: ?Sized + Storage> ScopedTypeMap<A> {
+    /// Create a new, empty ScopedTypeMap with a custom storage type and
+    /// no parent.
+    pub fn custom() -> ScopedTypeMap<A> {
+        ScopedTypeMap { local: TypeMap::custom(), parent: None }
+    }
+
+    /// Create a new, empty layer over `parent`.
+    pub fn with_parent(parent: Arc<ScopedTypeMap<A>>) -> ScopedTypeMap<A> {
+        ScopedTypeMap { local: TypeMap::custom(), parent: Some(parent) }
+    }
+
+    /// The parent layer, if any.
+    pub fn parent(&self) -> Option<&Arc<ScopedTypeMap<A>>> {
+        self.parent.as_ref()
+    }
+
+    /// The entries of this layer alone.
+    pub fn local(&self) -> &TypeMap<A> {
+        &self.local
+    }
+
+    /// The entries of this layer alone, mutably.
+    pub fn local_mut(&mut self) -> &mut TypeMap<A> {
+        &mut self.local
+    }
+
+    /// Insert a value into the local layer.
+    ///
+    /// Returns the value previously stored for the key in this layer, if
+    /// any. Values in parent layers are shadowed, not replaced.
+    pub fn insert<K: Key>(&mut self, val: K::Value) -> Option<K::Value>
+    where K::Value: Implements<A> {
+        self.local.insert::<K>(val)
+    }
+
+    /// Find a value in this layer or, failing that, the nearest parent
+    /// which has one.
+    pub fn get<K: Key>(&self) -> Option<&K::Value> {
+        self.try_get::<K>().ok()
+    }
+
+    /// Get a reference to the value for a key type from the nearest layer
+    /// which has one, distinguishing a missing key from a value of another
+    /// type.
+    pub fn try_get<K: Key>(&self) -> Result<&K::Value, TypeMapError> {
+        match (self.local.try_get::<K>(), &self.parent) {
+            (Err(TypeMapError::Missing { .. }), Some(parent)) => parent.try_get::<K>(),
+            (result, _) => result
+        }
+    }
+
+    /// Get a mutable reference to a value in the local layer.
+    ///
+    /// Parent layers are shared, so their values cannot be mutated.
+    pub fn get_mut<K: Key>(&mut self) -> Option<&mut K::Value> {
+        self.local.get_mut::<K>()
+    }
+
+    /// Check if this layer or any parent has a value for a key type.
+    pub fn contains<K: Key>(&self) -> bool {
+        self.local.contains::<K>() || self.parent.as_ref().is_some_and(|parent| parent.contains::<K>())
+    }
+
+    /// Remove a value from the local layer.
+    ///
+    /// Returns the removed value, if there was one. A value in a parent
+    /// layer is left in place, and is found by later lookups.
+    pub fn remove<K: Key>(&mut self) -> Option<K::Value> {
+        self.local.remove::<K>()
+    }
+
+    /// Copy every visible entry into a single TypeMap.
+    ///
+    /// Where several layers have a value for the same key, the one nearest
+    /// to this layer wins.
+    pub fn flatten(&self) -> TypeMap<A> where Box<A>: Clone {
+        let mut map = match self.parent {
+            Some(ref parent) => parent.flatten(),
+            None => TypeMap::custom()
+        };
+
+        for (&id, slot) in self.local.data.iter() {
+            map.data.insert(id, slot.clone());
+        }
+        map
+    }
+
+    /// Move every visible entry into a single TypeMap, without cloning.
+    ///
+    /// Where several layers have a value for the same key, the one nearest
+    /// to this layer wins. The entries of a parent can only be moved out if
+    /// nothing else shares it, so if any parent is still shared the map is
+    /// given back unchanged.
+    pub fn into_flat(self) -> Result<TypeMap<A>, ScopedTypeMap<A>> {
+        let ScopedTypeMap { local, parent } = self;
+        let mut map = match parent.map(Arc::try_unwrap) {
+            Some(Ok(parent)) => match parent.into_flat() {
+                Ok(map) => map,
+                Err(parent) => return Err(ScopedTypeMap { local, parent: Some(Arc::new(parent)) })
+            },
+            Some(Err(parent)) => return Err(ScopedTypeMap { local, parent: Some(parent) }),
+            None => TypeMap::custom()
+        };
+
+        for (id, slot) in local.data.into_iter() {
+            map.data.insert(id, slot);
+        }
+        Ok(map)
+    }
+}
+
+impl<A: ?Sized + Storage> Default for ScopedTypeMap<A> {
+    fn default() -> ScopedTypeMap<A> {
+        ScopedTypeMap::custom()
+    }
+}
+
+impl<A: ?Sized + Storage> fmt::Debug for ScopedTypeMap<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScopedTypeMap")
+            .field("local", &self.local)
+            .field("parent", &self.parent)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::{CloneAny, Key};
+
+    use super::ScopedTypeMap;
+
+    struct Config;
+
+    impl Key for Config { type Value = &'static str; }
+
+    struct Conn;
+
+    impl Key for Conn { type Value = u32; }
+
+    struct Request;
+
+    impl Key for Request { type Value = u64; }
+
+    fn layers() -> ScopedTypeMap<dyn CloneAny + Send + Sync> {
+        let mut global = ScopedTypeMap::custom();
+        global.insert::<Config>("global");
+        global.insert::<Conn>(0);
+
+        let mut conn = ScopedTypeMap::with_parent(Arc::new(global));
+        conn.insert::<Conn>(1);
+
+        let mut request = ScopedTypeMap::with_parent(Arc::new(conn));
+        request.insert::<Request>(2);
+        request
+    }
+
+    #[test] fn test_falls_through_to_parents() {
+        let mut request = layers();
+        assert_eq!(request.get::<Config>(), Some(&"global"));
+        assert_eq!(request.get::<Conn>(), Some(&1));
+        assert_eq!(request.get::<Request>(), Some(&2));
+        assert!(request.contains::<Config>());
+        assert!(request.get_mut::<Config>().is_none());
+
+        request.insert::<Config>("request");
+        assert_eq!(request.get::<Config>(), Some(&"request"));
+        assert_eq!(request.remove::<Config>(), Some("request"));
+        assert_eq!(request.remove::<Config>(), None);
+        assert_eq!(request.get::<Config>(), Some(&"global"));
+        assert!(!request.local().contains::<Config>());
+    }
+
+    #[test] fn test_flatten() {
+        let request = layers();
+        let flat = request.flatten();
+        assert_eq!(flat.len(), 3);
+        assert_eq!(flat.get::<Config>(), Some(&"global"));
+        assert_eq!(flat.get::<Conn>(), Some(&1));
+        assert_eq!(flat.get::<Request>(), Some(&2));
+        assert_eq!(request.parent().unwrap().local().len(), 1);
+    }
+
+    // Parents with `dyn Any` storage are only ever shared on one thread.
+    #[allow(clippy::arc_with_non_send_sync)]
+    #[test] fn test_into_flat() {
+        let mut global = ScopedTypeMap::new();
+        global.insert::<Config>("global");
+        global.insert::<Conn>(0);
+        let global = Arc::new(global);
+
+        let mut conn = ScopedTypeMap::with_parent(global.clone());
+        conn.insert::<Conn>(1);
+        let mut request = ScopedTypeMap::with_parent(Arc::new(conn));
+        request.insert::<Request>(2);
+
+        let request = request.into_flat().unwrap_err();
+        assert_eq!(request.get::<Conn>(), Some(&1));
+        drop(global);
+
+        let flat = request.into_flat().unwrap();
+        assert_eq!(flat.len(), 3);
+        assert_eq!(flat.get::<Config>(), Some(&"global"));
+        assert_eq!(flat.get::<Conn>(), Some(&1));
+        assert_eq!(flat.get::<Request>(), Some(&2));
+    }
+}
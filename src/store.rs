@@ -0,0 +1,263 @@
+use alloc::boxed::Box;
+#[cfg(not(feature = "std"))]
+use alloc::collections::btree_map::{self as map, BTreeMap};
+use core::any::TypeId;
+use core::hash::BuildHasher;
+use core::iter::{Chain, Map};
+#[cfg(not(feature = "std"))]
+use core::marker::PhantomData;
+use core::mem;
+use core::slice;
+#[cfg(feature = "std")]
+use std::collections::hash_map::{self as map, HashMap};
+
+use arrayvec::{self, ArrayVec};
+
+use crate::internals::Slot;
+
+/// The entries of a TypeMap.
+///
+/// Up to `N` entries are kept inline, sorted by `TypeId`. Inserting one
+/// more moves every entry to the spilled map, where they stay until it is
+/// emptied again, so at most one of the two is ever non-empty.
+///
+/// The spilled map is a `HashMap` using `S`, or a `BTreeMap` without the
+/// `std` feature.
+pub(crate) struct Store<A: ?Sized, S, const N: usize> {
+    inline: ArrayVec<(TypeId, Slot<A>), N>,
+    #[cfg(feature = "std")]
+    spilled: HashMap<TypeId, Slot<A>, S>,
+    #[cfg(not(feature = "std"))]
+    spilled: BTreeMap<TypeId, Slot<A>>,
+    #[cfg(not(feature = "std"))]
+    hasher: PhantomData<S>
+}
+
+type Pair<A> = (TypeId, Slot<A>);
+
+pub(crate) type Iter<'a, A> = Chain<
+    Map<slice::Iter<'a, Pair<A>>, fn(&'a Pair<A>) -> (&'a TypeId, &'a Slot<A>)>,
+    map::Iter<'a, TypeId, Slot<A>>
+>;
+
+pub(crate) type IterMut<'a, A> = Chain<
+    Map<slice::IterMut<'a, Pair<A>>, fn(&'a mut Pair<A>) -> (&'a TypeId, &'a mut Slot<A>)>,
+    map::IterMut<'a, TypeId, Slot<A>>
+>;
+
+#[cfg(feature = "std")]
+pub(crate) type Drain<'a, A, const N: usize> = Chain<
+    arrayvec::Drain<'a, Pair<A>, N>,
+    map::Drain<'a, TypeId, Slot<A>>
+>;
+
+#[cfg(not(feature = "std"))]
+pub(crate) type Drain<'a, A, const N: usize> = Chain<
+    arrayvec::Drain<'a, Pair<A>, N>,
+    map::IntoIter<TypeId, Slot<A>>
+>;
+
+pub(crate) type IntoIter<A, const N: usize> = Chain<
+    arrayvec::IntoIter<Pair<A>, N>,
+    map::IntoIter<TypeId, Slot<A>>
+>;
+
+/// A view into a single entry of a Store.
+pub(crate) enum RawEntry<'a, A: ?Sized, S, const N: usize> {
+    Occupied(RawOccupied<'a, A, N>),
+    Vacant(RawVacant<'a, A, S, N>)
+}
+
+pub(crate) enum RawOccupied<'a, A: ?Sized, const N: usize> {
+    Inline { inline: &'a mut ArrayVec<Pair<A>, N>, index: usize },
+    Spilled(map::OccupiedEntry<'a, TypeId, Slot<A>>)
+}
+
+pub(crate) enum RawVacant<'a, A: ?Sized, S, const N: usize> {
+    Inline { store: &'a mut Store<A, S, N>, id: TypeId, index: usize },
+    Spilled(map::VacantEntry<'a, TypeId, Slot<A>>)
+}
+
+impl<A: ?Sized, S, const N: usize> Store<A, S, N> {
+    #[cfg(feature = "std")]
+    pub(crate) fn with_hasher(hasher: S) -> Store<A, S, N> {
+        Store {
+            inline: ArrayVec::new(),
+            spilled: HashMap::with_hasher(hasher)
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub(crate) fn with_hasher(_: S) -> Store<A, S, N> {
+        Store {
+            inline: ArrayVec::new(),
+            spilled: BTreeMap::new(),
+            hasher: PhantomData
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.inline.len() + self.spilled.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.inline.is_empty() && self.spilled.is_empty()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.inline.clear();
+        self.spilled.clear();
+    }
+
+    pub(crate) fn iter(&self) -> Iter<'_, A> {
+        let inline: fn(&Pair<A>) -> (&TypeId, &Slot<A>) = |(id, slot)| (id, slot);
+        self.inline.iter().map(inline).chain(self.spilled.iter())
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> IterMut<'_, A> {
+        let inline: fn(&mut Pair<A>) -> (&TypeId, &mut Slot<A>) = |(id, slot)| (&*id, slot);
+        self.inline.iter_mut().map(inline).chain(self.spilled.iter_mut())
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn drain(&mut self) -> Drain<'_, A, N> {
+        self.inline.drain(..).chain(self.spilled.drain())
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub(crate) fn drain(&mut self) -> Drain<'_, A, N> {
+        let spilled = mem::take(&mut self.spilled);
+        self.inline.drain(..).chain(spilled)
+    }
+
+    pub(crate) fn into_iter(self) -> IntoIter<A, N> {
+        self.inline.into_iter().chain(self.spilled)
+    }
+
+    fn search(&self, id: &TypeId) -> Result<usize, usize> {
+        self.inline.binary_search_by(|(other, _)| other.cmp(id))
+    }
+}
+
+impl<A: ?Sized, S: BuildHasher, const N: usize> Store<A, S, N> {
+    pub(crate) fn get(&self, id: &TypeId) -> Option<&Slot<A>> {
+        if !self.spilled.is_empty() { return self.spilled.get(id) }
+        self.search(id).ok().map(|index| &self.inline[index].1)
+    }
+
+    pub(crate) fn get_mut(&mut self, id: &TypeId) -> Option<&mut Slot<A>> {
+        if !self.spilled.is_empty() { return self.spilled.get_mut(id) }
+        self.search(id).ok().map(move |index| &mut self.inline[index].1)
+    }
+
+    /// Get mutable references to the slots of several distinct keys.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ids` contains duplicates.
+    pub(crate) fn get_disjoint_mut<const M: usize>(&mut self, ids: [&TypeId; M]) -> [Option<&mut Slot<A>>; M] {
+        #[cfg(feature = "std")]
+        if !self.spilled.is_empty() { return self.spilled.get_disjoint_mut(ids) }
+
+        let mut slots = [const { None }; M];
+        for (id, slot) in self.iter_mut() {
+            if let Some(index) = ids.iter().position(|&wanted| wanted == id) {
+                slots[index] = Some(slot);
+            }
+        }
+        slots
+    }
+
+    pub(crate) fn contains_key(&self, id: &TypeId) -> bool {
+        self.get(id).is_some()
+    }
+
+    pub(crate) fn insert(&mut self, id: TypeId, slot: Slot<A>) -> Option<Slot<A>> {
+        match self.entry(id) {
+            RawEntry::Occupied(entry) => Some(mem::replace(entry.into_mut(), slot)),
+            RawEntry::Vacant(entry) => {
+                entry.insert(slot);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, id: &TypeId) -> Option<Slot<A>> {
+        if !self.spilled.is_empty() { return self.spilled.remove(id) }
+        self.search(id).ok().map(|index| self.inline.remove(index).1)
+    }
+
+    pub(crate) fn entry(&mut self, id: TypeId) -> RawEntry<'_, A, S, N> {
+        if !self.spilled.is_empty() {
+            return match self.spilled.entry(id) {
+                map::Entry::Occupied(entry) => RawEntry::Occupied(RawOccupied::Spilled(entry)),
+                map::Entry::Vacant(entry) => RawEntry::Vacant(RawVacant::Spilled(entry))
+            };
+        }
+
+        match self.search(&id) {
+            Ok(index) => RawEntry::Occupied(RawOccupied::Inline { inline: &mut self.inline, index }),
+            Err(index) => RawEntry::Vacant(RawVacant::Inline { store: self, id, index })
+        }
+    }
+}
+
+impl<A: ?Sized, S: Clone, const N: usize> Clone for Store<A, S, N> where Box<A>: Clone {
+    fn clone(&self) -> Store<A, S, N> {
+        Store {
+            inline: self.inline.clone(),
+            spilled: self.spilled.clone(),
+            #[cfg(not(feature = "std"))]
+            hasher: PhantomData
+        }
+    }
+}
+
+impl<'a, A: ?Sized, const N: usize> RawOccupied<'a, A, N> {
+    pub(crate) fn get(&self) -> &Slot<A> {
+        match *self {
+            RawOccupied::Inline { ref inline, index } => &inline[index].1,
+            RawOccupied::Spilled(ref entry) => entry.get()
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut Slot<A> {
+        match *self {
+            RawOccupied::Inline { ref mut inline, index } => &mut inline[index].1,
+            RawOccupied::Spilled(ref mut entry) => entry.get_mut()
+        }
+    }
+
+    pub(crate) fn into_mut(self) -> &'a mut Slot<A> {
+        match self {
+            RawOccupied::Inline { inline, index } => &mut inline[index].1,
+            RawOccupied::Spilled(entry) => entry.into_mut()
+        }
+    }
+
+    pub(crate) fn remove(self) -> Slot<A> {
+        match self {
+            RawOccupied::Inline { inline, index } => inline.remove(index).1,
+            RawOccupied::Spilled(entry) => entry.remove()
+        }
+    }
+}
+
+impl<'a, A: ?Sized, S: BuildHasher, const N: usize> RawVacant<'a, A, S, N> {
+    pub(crate) fn insert(self, slot: Slot<A>) -> &'a mut Slot<A> {
+        match self {
+            RawVacant::Inline { store, id, index } => {
+                if store.inline.len() < N {
+                    store.inline.insert(index, (id, slot));
+                    return &mut store.inline[index].1;
+                }
+
+                #[cfg(feature = "std")]
+                store.spilled.reserve(N + 1);
+                store.spilled.extend(store.inline.drain(..));
+                store.spilled.entry(id).or_insert(slot)
+            },
+            RawVacant::Spilled(entry) => entry.insert(slot)
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! Changes to a TypeMap which can be undone as a whole.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::{Any, TypeId};
+use core::hash::BuildHasher;
+use core::ops::Deref;
+
+use crate::internals::Slot;
+use crate::{BuildTypeIdHasher, Implements, Key, Storage, TypeMap};
+
+/// A set of changes to a TypeMap, created by `TypeMap::transaction`.
+///
+/// The changes are made to the map immediately, and can be read back
+/// through the transaction. Unless `commit` is called, they are undone
+/// when the transaction is dropped, including when a panic unwinds past
+/// it.
+pub struct Transaction<'a, A = dyn Any, S = BuildTypeIdHasher, const N: usize = 0>
+where A: ?Sized + Storage, S: BuildHasher {
+    map: &'a mut TypeMap<A, S, N>,
+    // The value of each key changed so far, from before its first change.
+    undo: Vec<(TypeId, Option<Slot<A>>)>
+}
+
+impl<A: ?Sized + Storage, S: BuildHasher, const N: usize> TypeMap<A, S, N> {
+    /// Start a transaction, whose changes to the map are undone unless it
+    /// is committed.
+    pub fn transaction(&mut self) -> Transaction<'_, A, S, N> {
+        Transaction { map: self, undo: Vec::new() }
+    }
+}
+
+impl<'a, A: ?Sized + Storage, S: BuildHasher, const N: usize> Transaction<'a, A, S, N> {
+    /// Insert a value into the map with a specified key type.
+    ///
+    /// Returns true if this replaced a value. The replaced value is kept
+    /// until the transaction ends, in case it must be restored.
+    pub fn insert<K: Key>(&mut self, val: K::Value) -> bool
+    where K::Value: Implements<A> {
+        let id = TypeId::of::<K>();
+        let old = self.map.data.insert(id, Slot::new::<K, K::Value>(val));
+        let replaced = old.is_some();
+        self.log(id, old);
+        replaced
+    }
+
+    /// Remove a value from the map.
+    ///
+    /// Returns true if there was a value. The removed value is kept until
+    /// the transaction ends, in case it must be restored.
+    pub fn remove<K: Key>(&mut self) -> bool {
+        let id = TypeId::of::<K>();
+        if !self.map.data.get(&id).is_some_and(|slot| slot.holds::<K::Value>()) { return false }
+
+        let old = self.map.data.remove(&id);
+        self.log(id, old);
+        true
+    }
+
+    /// Find a value in the map and get a mutable reference to it.
+    ///
+    /// The first time a key's value is borrowed mutably, a clone of it is
+    /// kept to restore if the transaction is not committed.
+    pub fn get_mut<K: Key>(&mut self) -> Option<&mut K::Value> where Box<A>: Clone {
+        let id = TypeId::of::<K>();
+        if !self.logged(id) {
+            let slot = self.map.data.get(&id).filter(|slot| slot.holds::<K::Value>())?;
+            let original = slot.clone();
+            self.undo.push((id, Some(original)));
+        }
+        self.map.get_mut::<K>()
+    }
+
+    /// Keep the changes made in the transaction.
+    pub fn commit(mut self) {
+        self.undo.clear();
+    }
+
+    /// Undo the changes made in the transaction.
+    ///
+    /// This is the same as dropping the transaction.
+    pub fn rollback(self) {}
+
+    fn logged(&self, id: TypeId) -> bool {
+        self.undo.iter().any(|&(logged, _)| logged == id)
+    }
+
+    fn log(&mut self, id: TypeId, original: Option<Slot<A>>) {
+        if !self.logged(id) {
+            self.undo.push((id, original));
+        }
+    }
+}
+
+impl<'a, A: ?Sized + Storage, S: BuildHasher, const N: usize> Deref for Transaction<'a, A, S, N> {
+    type Target = TypeMap<A, S, N>;
+
+    fn deref(&self) -> &TypeMap<A, S, N> {
+        self.map
+    }
+}
+
+impl<'a, A: ?Sized + Storage, S: BuildHasher, const N: usize> Drop for Transaction<'a, A, S, N> {
+    fn drop(&mut self) {
+        for (id, original) in self.undo.drain(..) {
+            match original {
+                Some(slot) => { self.map.data.insert(id, slot); },
+                None => { self.map.data.remove(&id); }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use crate::{CloneTypeMap, Key, TypeMap};
+
+    struct Balance;
+
+    impl Key for Balance { type Value = i64; }
+
+    struct Audit;
+
+    impl Key for Audit { type Value = Vec<&'static str>; }
+
+    fn map() -> CloneTypeMap {
+        let mut map = CloneTypeMap::custom();
+        map.insert::<Balance>(100);
+        map
+    }
+
+    #[test] fn test_rollback_on_drop() {
+        let mut map = map();
+        {
+            let mut tx = map.transaction();
+            assert!(tx.insert::<Balance>(50));
+            assert!(!tx.insert::<Audit>(vec!["debit"]));
+            tx.get_mut::<Audit>().unwrap().push("credit");
+            assert!(tx.insert::<Balance>(25));
+            assert_eq!(tx.get::<Balance>(), Some(&25));
+            assert_eq!(tx.len(), 2);
+        }
+        assert_eq!(map.get::<Balance>(), Some(&100));
+        assert!(!map.contains::<Audit>());
+
+        let mut tx = map.transaction();
+        assert!(tx.remove::<Balance>());
+        assert!(!tx.remove::<Balance>());
+        tx.rollback();
+        assert_eq!(map.get::<Balance>(), Some(&100));
+    }
+
+    #[test] fn test_commit() {
+        let mut map = map();
+        let mut tx = map.transaction();
+        *tx.get_mut::<Balance>().unwrap() -= 30;
+        tx.insert::<Audit>(vec!["debit"]);
+        tx.commit();
+        assert_eq!(map.get::<Balance>(), Some(&70));
+        assert_eq!(map.get::<Audit>().unwrap(), &["debit"]);
+    }
+
+    #[test] fn test_rollback_on_panic() {
+        let mut map = map();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut tx = map.transaction();
+            *tx.get_mut::<Balance>().unwrap() = 0;
+            tx.remove::<Balance>();
+            panic!("handler failed");
+        }));
+        assert!(result.is_err());
+        assert_eq!(map.get::<Balance>(), Some(&100));
+    }
+
+    #[test] fn test_without_clone() {
+        let mut map = TypeMap::new();
+        map.insert::<Balance>(1);
+        let mut tx = map.transaction();
+        tx.insert::<Balance>(2);
+        tx.commit();
+        assert_eq!(map.get::<Balance>(), Some(&2));
+    }
+}